@@ -0,0 +1,641 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::instruction::{Flag, Instruction};
+
+/*
+    source syntax, one statement per line:
+    - `; comment` runs to the end of the line
+    - `name:` defines a label at the current address, optionally followed by a statement
+    - `.org ADDRESS` moves the current address
+    - `.byte V, V, ...` emits bytes, `.word V, V, ...` emits big-endian words (labels allowed)
+    - instructions use the same syntax `Instruction` displays, e.g. `add r0, r1 -> r2`,
+      `ori r0, 0xF -> r2`, `mov r0, r1`, `sub r0, r1`, `shl r0, r0`, `sei r0, 5`, `sfs c`, `call fn_add`, `ret`, `halt`,
+      `ldi table`, `ld r0, [i + r1]`, `st r0, [i]`, `ldm r3`, `add i, r2`
+    - numbers are decimal, `0x` hex or `0b` binary; mnemonics and registers are case-insensitive
+*/
+
+const MEMORY_SIZE: usize = 0x1000;
+
+/// The memory image produced by [`assemble`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// Address of `bytes[0]`.
+    pub origin: u16,
+    /// Everything from the lowest to the highest emitted address; gaps are zero-filled.
+    pub bytes: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+    /// Source line (1-based) of every instruction, mapped to its address.
+    pub line_addresses: BTreeMap<usize, u16>,
+}
+
+/// An assembly error. `line` and `column` are 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    // first pass: parse every line, assign addresses and collect labels
+    let mut statements: Vec<(usize, u16, Statement)> = Vec::new();
+    let mut labels: BTreeMap<String, u16> = BTreeMap::new();
+    let mut address: usize = 0;
+
+    for (index, text) in source.lines().enumerate() {
+        let line: usize = index + 1;
+        let mut parser: Parser = Parser::new(tokenize(text, line)?, line, text.len());
+
+        while let Some((name, column)) = parser.label() {
+            if labels.insert(name.clone(), address as u16).is_some() {
+                return Err(parser.error_at(column, format!("label `{}` is already defined", name)));
+            }
+        }
+        if parser.at_end() {
+            continue;
+        }
+
+        let column: usize = parser.column();
+        let statement: Statement = parser.statement()?;
+        parser.expect_end()?;
+        if let Statement::Org(origin) = statement {
+            address = origin as usize;
+            continue;
+        }
+        let size: usize = statement.size();
+        if address + size > MEMORY_SIZE {
+            return Err(parser.error_at(column, format!("address 0x{:x} is outside of memory", address + size - 1)));
+        }
+        statements.push((line, address as u16, statement));
+        address += size;
+    }
+
+    // second pass: resolve labels and write the image
+    let mut image: Vec<Option<u8>> = vec![None; MEMORY_SIZE];
+    let mut line_addresses: BTreeMap<usize, u16> = BTreeMap::new();
+    for (line, address, statement) in statements {
+        let bytes: Vec<u8> = statement.emit(&labels, line)?;
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            let slot: &mut Option<u8> = &mut image[address as usize + offset];
+            if slot.is_some() {
+                return Err(AsmError {
+                    line,
+                    column: 1,
+                    message: format!("address 0x{:03x} is already in use", address as usize + offset),
+                });
+            }
+            *slot = Some(byte);
+        }
+        if let Statement::Instruction { .. } = statement {
+            line_addresses.insert(line, address);
+        }
+    }
+
+    let start: usize = image.iter().position(Option::is_some).unwrap_or(0);
+    let end: usize = image.iter().rposition(Option::is_some).map_or(0, |last| last + 1);
+    Ok(Program {
+        origin: start as u16,
+        bytes: image[start..end].iter().map(|byte| byte.unwrap_or(0)).collect(),
+        labels,
+        line_addresses,
+    })
+}
+
+/// A numeric literal or a label reference, resolved in the second pass.
+#[derive(Debug, Clone)]
+enum Value {
+    Number(u32),
+    Label { name: String, column: usize },
+}
+
+impl Value {
+    fn resolve(&self, labels: &BTreeMap<String, u16>, line: usize) -> Result<u32, AsmError> {
+        match self {
+            Value::Number(number) => Ok(*number),
+            Value::Label { name, column } => labels.get(name).map(|address| *address as u32).ok_or_else(|| AsmError {
+                line,
+                column: *column,
+                message: format!("undefined label `{}`", name),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    /// `target`, if any, is the address operand still to be resolved.
+    Instruction { instruction: Instruction, target: Option<(Value, usize)> },
+    Bytes(Vec<u8>),
+    Words(Vec<(Value, usize)>),
+    Org(u16),
+}
+
+impl Statement {
+    fn size(&self) -> usize {
+        match self {
+            Statement::Instruction { .. } => 2,
+            Statement::Bytes(bytes) => bytes.len(),
+            Statement::Words(words) => words.len() * 2,
+            Statement::Org(_) => 0,
+        }
+    }
+
+    fn emit(&self, labels: &BTreeMap<String, u16>, line: usize) -> Result<Vec<u8>, AsmError> {
+        match self {
+            Statement::Instruction { instruction, target } => {
+                let mut instruction: Instruction = *instruction;
+                if let Some((value, column)) = target {
+                    let address: u32 = value.resolve(labels, line)?;
+                    if address > 0xFFF {
+                        return Err(AsmError { line, column: *column, message: format!("address 0x{:x} does not fit in 12 bits", address) });
+                    }
+                    instruction = with_address(instruction, address as u16);
+                }
+                Ok(instruction.encode().to_be_bytes().to_vec())
+            }
+            Statement::Bytes(bytes) => Ok(bytes.clone()),
+            Statement::Words(words) => {
+                let mut bytes: Vec<u8> = Vec::new();
+                for (value, column) in words {
+                    let word: u32 = value.resolve(labels, line)?;
+                    if word > 0xFFFF {
+                        return Err(AsmError { line, column: *column, message: format!("value 0x{:x} does not fit in 16 bits", word) });
+                    }
+                    bytes.extend_from_slice(&(word as u16).to_be_bytes());
+                }
+                Ok(bytes)
+            }
+            Statement::Org(_) => Ok(Vec::new()),
+        }
+    }
+}
+
+fn with_address(instruction: Instruction, address: u16) -> Instruction {
+    match instruction {
+        Instruction::Jump { .. } => Instruction::Jump { address },
+        Instruction::Call { .. } => Instruction::Call { address },
+        Instruction::SetIndex { .. } => Instruction::SetIndex { address },
+        other => other,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u32),
+    Comma,
+    Colon,
+    Arrow,
+    Plus,
+    LeftBracket,
+    RightBracket,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Number(number) => write!(f, "`{}`", number),
+            Token::Comma => write!(f, "`,`"),
+            Token::Colon => write!(f, "`:`"),
+            Token::Arrow => write!(f, "`->`"),
+            Token::Plus => write!(f, "`+`"),
+            Token::LeftBracket => write!(f, "`[`"),
+            Token::RightBracket => write!(f, "`]`"),
+        }
+    }
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<(Token, usize)>, AsmError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens: Vec<(Token, usize)> = Vec::new();
+    let mut i: usize = 0;
+    while i < chars.len() {
+        let c: char = chars[i];
+        let column: usize = i + 1;
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == ',' {
+            tokens.push((Token::Comma, column));
+            i += 1;
+        } else if c == ':' {
+            tokens.push((Token::Colon, column));
+            i += 1;
+        } else if c == '+' {
+            tokens.push((Token::Plus, column));
+            i += 1;
+        } else if c == '[' {
+            tokens.push((Token::LeftBracket, column));
+            i += 1;
+        } else if c == ']' {
+            tokens.push((Token::RightBracket, column));
+            i += 1;
+        } else if c == '-' && chars.get(i + 1) == Some(&'>') {
+            tokens.push((Token::Arrow, column));
+            i += 2;
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let start: usize = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if c.is_ascii_digit() {
+                let number: u32 = parse_number(&word).ok_or_else(|| AsmError { line, column, message: format!("invalid number `{}`", word) })?;
+                tokens.push((Token::Number(number), column));
+            } else {
+                tokens.push((Token::Ident(word), column));
+            }
+        } else {
+            return Err(AsmError { line, column, message: format!("unexpected character `{}`", c) });
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Option<u32> {
+    let lower: String = word.to_ascii_lowercase().replace('_', "");
+    if let Some(hex) = lower.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse::<u32>().ok()
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    line: usize,
+    end_column: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<(Token, usize)>, line: usize, line_length: usize) -> Parser {
+        Parser { tokens, position: 0, line, end_column: line_length + 1 }
+    }
+
+    fn error_at(&self, column: usize, message: String) -> AsmError {
+        AsmError { line: self.line, column, message }
+    }
+
+    fn at_end(&self) -> bool {
+        self.position == self.tokens.len()
+    }
+
+    // the column of the next token, or of the end of the line
+    fn column(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.end_column, |(_, column)| *column)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<(Token, usize), AsmError> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token.clone())
+            }
+            None => Err(self.error_at(self.end_column, format!("expected {}", expected))),
+        }
+    }
+
+    fn expect_end(&self) -> Result<(), AsmError> {
+        match self.tokens.get(self.position) {
+            Some((token, column)) => Err(self.error_at(*column, format!("unexpected {}", token))),
+            None => Ok(()),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), AsmError> {
+        let (token, column) = self.next(&expected.to_string())?;
+        if token != expected {
+            return Err(self.error_at(column, format!("expected {}, found {}", expected, token)));
+        }
+        Ok(())
+    }
+
+    fn label(&mut self) -> Option<(String, usize)> {
+        match self.tokens.get(self.position..self.position + 2) {
+            Some([(Token::Ident(name), column), (Token::Colon, _)]) if !name.starts_with('.') => {
+                let label: (String, usize) = (name.clone(), *column);
+                self.position += 2;
+                Some(label)
+            }
+            _ => None,
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let (token, column) = self.next("a register")?;
+        if let Token::Ident(name) = &token {
+            let register: Option<u8> = name
+                .strip_prefix(['r', 'R'])
+                .and_then(|number| number.parse::<u8>().ok())
+                .filter(|register| *register < 16);
+            if let Some(register) = register {
+                return Ok(register);
+            }
+        }
+        Err(self.error_at(column, format!("expected a register, found {}", token)))
+    }
+
+    // consumes the index register operand `i` if it is next
+    fn index_register(&mut self) -> bool {
+        match self.tokens.get(self.position) {
+            Some((Token::Ident(name), _)) if name.eq_ignore_ascii_case("i") => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // `[i]` or `[i + rY]`, returning Y for the indexed form
+    fn memory_operand(&mut self) -> Result<Option<u8>, AsmError> {
+        self.expect(Token::LeftBracket)?;
+        if !self.index_register() {
+            let (token, column) = self.next("`i`")?;
+            return Err(self.error_at(column, format!("expected `i`, found {}", token)));
+        }
+        let mut offset: Option<u8> = None;
+        if let Some((Token::Plus, _)) = self.tokens.get(self.position) {
+            self.position += 1;
+            offset = Some(self.register()?);
+        }
+        self.expect(Token::RightBracket)?;
+        Ok(offset)
+    }
+
+    fn flag(&mut self) -> Result<Flag, AsmError> {
+        let (token, column) = self.next("a flag")?;
+        if let Token::Ident(name) = &token {
+            if let Some(flag) = Flag::from_name(name) {
+                return Ok(flag);
+            }
+        }
+        Err(self.error_at(column, format!("expected one of the flags z, c, v, n, found {}", token)))
+    }
+
+    fn number(&mut self, max: u32) -> Result<u32, AsmError> {
+        let (token, column) = self.next("a number")?;
+        match token {
+            Token::Number(number) if number <= max => Ok(number),
+            Token::Number(number) => Err(self.error_at(column, format!("value 0x{:x} is larger than 0x{:x}", number, max))),
+            other => Err(self.error_at(column, format!("expected a number, found {}", other))),
+        }
+    }
+
+    fn value(&mut self) -> Result<(Value, usize), AsmError> {
+        let (token, column) = self.next("a number or label")?;
+        match token {
+            Token::Number(number) => Ok((Value::Number(number), column)),
+            Token::Ident(name) if !name.starts_with('.') => Ok((Value::Label { name, column }, column)),
+            other => Err(self.error_at(column, format!("expected a number or label, found {}", other))),
+        }
+    }
+
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Parser) -> Result<T, AsmError>) -> Result<Vec<T>, AsmError> {
+        let mut items: Vec<T> = vec![item(self)?];
+        while !self.at_end() {
+            self.expect(Token::Comma)?;
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn statement(&mut self) -> Result<Statement, AsmError> {
+        let (token, column) = self.next("a statement")?;
+        let word: String = match token {
+            Token::Ident(word) => word.to_ascii_lowercase(),
+            other => return Err(self.error_at(column, format!("expected a mnemonic or directive, found {}", other))),
+        };
+        let instruction: Instruction = match word.as_str() {
+            ".org" => return Ok(Statement::Org(self.number(MEMORY_SIZE as u32)? as u16)),
+            ".byte" => return Ok(Statement::Bytes(self.list(|parser| parser.number(0xFF).map(|byte| byte as u8))?)),
+            ".word" => return Ok(Statement::Words(self.list(Parser::value)?)),
+            "jmp" | "call" | "ldi" => {
+                let target: (Value, usize) = self.value()?;
+                let instruction: Instruction = match word.as_str() {
+                    "jmp" => Instruction::Jump { address: 0 },
+                    "call" => Instruction::Call { address: 0 },
+                    _ => Instruction::SetIndex { address: 0 },
+                };
+                return Ok(Statement::Instruction { instruction, target: Some(target) });
+            }
+            "add" if self.index_register() => {
+                self.expect(Token::Comma)?;
+                Instruction::AddIndex { x: self.register()? }
+            }
+            "add" | "or" | "and" => {
+                let x: u8 = self.register()?;
+                self.expect(Token::Comma)?;
+                let y: u8 = self.register()?;
+                self.expect(Token::Arrow)?;
+                let z: u8 = self.register()?;
+                match word.as_str() {
+                    "add" => Instruction::Add { x, y, z },
+                    "or" => Instruction::Or { x, y, z },
+                    _ => Instruction::And { x, y, z },
+                }
+            }
+            "addi" | "ori" | "andi" => {
+                let x: u8 = self.register()?;
+                self.expect(Token::Comma)?;
+                let value: u8 = self.number(0xF)? as u8;
+                self.expect(Token::Arrow)?;
+                let z: u8 = self.register()?;
+                match word.as_str() {
+                    "addi" => Instruction::AddImm { x, value, z },
+                    "ori" => Instruction::OrImm { x, value, z },
+                    _ => Instruction::AndImm { x, value, z },
+                }
+            }
+            "mov" => {
+                let x: u8 = self.register()?;
+                self.expect(Token::Comma)?;
+                Instruction::Mov { x, y: self.register()? }
+            }
+            "movi" => {
+                let x: u8 = self.register()?;
+                self.expect(Token::Comma)?;
+                Instruction::MovImm { x, value: self.number(0xF)? as u8 }
+            }
+            "sub" | "sbc" | "xor" | "not" | "shl" | "shr" | "rol" | "ror" | "mul" | "div" | "mod" => {
+                let x: u8 = self.register()?;
+                self.expect(Token::Comma)?;
+                let y: u8 = self.register()?;
+                match word.as_str() {
+                    "sub" => Instruction::Sub { x, y },
+                    "sbc" => Instruction::SubBorrow { x, y },
+                    "xor" => Instruction::Xor { x, y },
+                    "not" => Instruction::Not { x, y },
+                    "shl" => Instruction::ShiftLeft { x, y },
+                    "shr" => Instruction::ShiftRight { x, y },
+                    "rol" => Instruction::RotateLeft { x, y },
+                    "ror" => Instruction::RotateRight { x, y },
+                    "mul" => Instruction::Mul { x, y },
+                    "div" => Instruction::Div { x, y },
+                    _ => Instruction::Mod { x, y },
+                }
+            }
+            "se" | "sne" => {
+                let x: u8 = self.register()?;
+                self.expect(Token::Comma)?;
+                let y: u8 = self.register()?;
+                if word == "se" {
+                    Instruction::SkipEq { x, y }
+                } else {
+                    Instruction::SkipNe { x, y }
+                }
+            }
+            "sei" | "snei" => {
+                let x: u8 = self.register()?;
+                self.expect(Token::Comma)?;
+                let value: u8 = self.number(0xF)? as u8;
+                if word == "sei" {
+                    Instruction::SkipEqImm { x, value }
+                } else {
+                    Instruction::SkipNeImm { x, value }
+                }
+            }
+            "sfs" | "sfc" => {
+                let flag: Flag = self.flag()?;
+                if word == "sfs" {
+                    Instruction::SkipIfSet { flag }
+                } else {
+                    Instruction::SkipIfClear { flag }
+                }
+            }
+            "ld" | "st" => {
+                let x: u8 = self.register()?;
+                self.expect(Token::Comma)?;
+                match (word.as_str(), self.memory_operand()?) {
+                    ("ld", Some(y)) => Instruction::LoadIndexed { x, y },
+                    ("ld", None) => Instruction::Load { x },
+                    (_, Some(y)) => Instruction::StoreIndexed { x, y },
+                    (_, None) => Instruction::Store { x },
+                }
+            }
+            "ldm" => Instruction::LoadBlock { x: self.register()? },
+            "stm" => Instruction::StoreBlock { x: self.register()? },
+            "ret" => Instruction::Ret,
+            "reti" => Instruction::ReturnFromInterrupt,
+            "ei" => Instruction::EnableInterrupts,
+            "di" => Instruction::DisableInterrupts,
+            "nop" => Instruction::Nop,
+            "halt" => Instruction::Halt,
+            _ if word.starts_with('.') => return Err(self.error_at(column, format!("unknown directive `{}`", word))),
+            _ => return Err(self.error_at(column, format!("unknown mnemonic `{}`", word))),
+        };
+        Ok(Statement::Instruction { instruction, target: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_instructions() {
+        let program: Program = assemble(
+            "
+            add r0, r1 -> r2
+            ORI r0, 0xF -> r2
+            mov r0, r1
+            movi r10, 15
+            halt
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program.origin, 0);
+        assert_eq!(program.bytes, vec![0x10, 0x12, 0x40, 0xF2, 0x70, 0x10, 0x8A, 0xF0, 0x00, 0x00]);
+        assert_eq!(program.line_addresses.get(&2), Some(&0));
+        assert_eq!(program.line_addresses.get(&6), Some(&8));
+    }
+
+    #[test]
+    fn test_assemble_labels_and_directives() {
+        let program: Program = assemble(
+            "
+            .org 0x100      ; entry point
+            start: call fn_add
+                   halt
+            fn_add:
+                   add r0, r1 -> r2
+                   ret
+            table: .byte 1, 0x2, 0b11
+                   .word start, 0xBEEF
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program.origin, 0x100);
+        assert_eq!(program.labels["start"], 0x100);
+        assert_eq!(program.labels["fn_add"], 0x104);
+        assert_eq!(program.labels["table"], 0x108);
+        assert_eq!(
+            program.bytes,
+            vec![0xA1, 0x04, 0x00, 0x00, 0x10, 0x12, 0xB0, 0x00, 0x01, 0x02, 0x03, 0x01, 0x00, 0xBE, 0xEF]
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = |source: &str| assemble(source).unwrap_err();
+
+        assert_eq!(error("  foo r1").to_string(), "1:3: unknown mnemonic `foo`");
+        assert_eq!(error("nop\nadd r0, r16 -> r2").to_string(), "2:9: expected a register, found `r16`");
+        assert_eq!(error("addi r0, 16 -> r1").to_string(), "1:10: value 0x10 is larger than 0xf");
+        assert_eq!(error("add r0, r1").to_string(), "1:11: expected `->`");
+        assert_eq!(error("jmp nowhere").to_string(), "1:5: undefined label `nowhere`");
+        assert_eq!(error("a: nop\na: nop").to_string(), "2:1: label `a` is already defined");
+        assert_eq!(error("nop\n.org 0\nhalt").to_string(), "3:1: address 0x000 is already in use");
+        assert_eq!(error(".org 0xfff\nend: halt").to_string(), "2:6: address 0x1000 is outside of memory");
+        assert_eq!(error("halt $").to_string(), "1:6: unexpected character `$`");
+        assert_eq!(error("ret r1").to_string(), "1:5: unexpected `r1`");
+        assert_eq!(error("ld r0, [r1]").to_string(), "1:9: expected `i`, found `r1`");
+        assert_eq!(error("st r0, [i + r1").to_string(), "1:15: expected `]`");
+    }
+
+    #[test]
+    fn test_assemble_memory_access() {
+        let program: Program = assemble(
+            "
+            ldi table
+            ld r0, [i]
+            st r1, [I + r2]
+            ldm r3
+            add i, r4
+            table: .byte 0xAA
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(program.bytes, vec![0xE0, 0x0A, 0xF0, 0x02, 0xF1, 0x21, 0xF3, 0x04, 0xF4, 0x06, 0xAA]);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        // 2048 opcodes fill memory exactly, so assemble them in chunks of that size
+        let opcodes: Vec<u16> = (0..=0xFFFF_u16).collect();
+        for chunk in opcodes.chunks(2048) {
+            let instructions: Vec<Instruction> = chunk.iter().map(|opcode| Instruction::decode(*opcode)).collect();
+            let source: String = instructions.iter().map(|instruction| format!("{}\n", instruction)).collect();
+            let program: Program = assemble(&source).unwrap();
+            let expected: Vec<u8> = instructions.iter().flat_map(|instruction| instruction.encode().to_be_bytes()).collect();
+            assert_eq!(program.bytes, expected);
+        }
+    }
+}
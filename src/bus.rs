@@ -0,0 +1,295 @@
+use std::io;
+use std::ops::RangeInclusive;
+
+use crate::console::{self, Console};
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+use crate::timer::{self, Timer};
+
+/// Size of the 12-bit address space the CPU drives.
+pub const ADDRESS_SPACE: usize = 0x1000;
+
+/// Memory and devices as seen by the CPU.
+///
+/// `read_u8`/`write_u8` are the accesses a running program makes and may have device side
+/// effects. `peek_u8`/`poke_u8` are for loaders and debuggers: they never trigger side effects
+/// and `poke_u8` ignores write protection.
+pub trait Bus {
+    fn read_u8(&mut self, address: u16) -> io::Result<u8>;
+
+    fn write_u8(&mut self, address: u16, value: u8) -> io::Result<()>;
+
+    /// Reads a big-endian word, the byte order of opcodes.
+    fn read_u16(&mut self, address: u16) -> io::Result<u16> {
+        let high: u8 = self.read_u8(address)?;
+        let low: u8 = self.read_u8(address.wrapping_add(1))?;
+        Ok(u16::from_be_bytes([high, low]))
+    }
+
+    fn peek_u8(&self, address: u16) -> u8;
+
+    fn poke_u8(&mut self, address: u16, value: u8);
+
+    fn peek_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|offset| self.peek_u8(start.wrapping_add(offset as u16))).collect()
+    }
+
+    /// Advances time-driven devices by the cycles the CPU just spent.
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// Interrupt lines raised since the last call, one bit per line.
+    fn take_interrupts(&mut self) -> u8 {
+        0
+    }
+
+    /// Writes the device's state into a save state. Stateless devices write nothing.
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    /// Restores what `save_state` wrote.
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+fn load_bytes(bytes: &mut [u8], state: &mut StateReader, device: &str) -> Result<(), SnapshotError> {
+    let saved: &[u8] = state.get_bytes()?;
+    if saved.len() != bytes.len() {
+        return Err(SnapshotError::Mismatch(format!("{} of {} bytes, expected {}", device, saved.len(), bytes.len())));
+    }
+    bytes.copy_from_slice(saved);
+    Ok(())
+}
+
+/// Plain read/write memory. Accesses past the end read 0 and are otherwise ignored.
+pub struct Ram {
+    bytes: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Ram {
+        Ram { bytes: vec![0; size] }
+    }
+}
+
+impl Default for Ram {
+    /// RAM covering the whole address space.
+    fn default() -> Self {
+        Ram::new(ADDRESS_SPACE)
+    }
+}
+
+impl Bus for Ram {
+    fn read_u8(&mut self, address: u16) -> io::Result<u8> {
+        Ok(self.peek_u8(address))
+    }
+
+    fn write_u8(&mut self, address: u16, value: u8) -> io::Result<()> {
+        self.poke_u8(address, value);
+        Ok(())
+    }
+
+    fn peek_u8(&self, address: u16) -> u8 {
+        self.bytes.get(address as usize).copied().unwrap_or(0)
+    }
+
+    fn poke_u8(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.bytes.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.put_bytes(&self.bytes);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SnapshotError> {
+        load_bytes(&mut self.bytes, state, "RAM")
+    }
+}
+
+/// Read-only memory. Program writes are ignored; only `poke_u8` can change the contents.
+pub struct Rom {
+    bytes: Vec<u8>,
+}
+
+impl Rom {
+    pub fn new(bytes: Vec<u8>) -> Rom {
+        Rom { bytes }
+    }
+}
+
+impl Bus for Rom {
+    fn read_u8(&mut self, address: u16) -> io::Result<u8> {
+        Ok(self.peek_u8(address))
+    }
+
+    fn write_u8(&mut self, _address: u16, _value: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peek_u8(&self, address: u16) -> u8 {
+        self.bytes.get(address as usize).copied().unwrap_or(0)
+    }
+
+    fn poke_u8(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.bytes.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+
+    // saved too, since a debugger may have poked it
+    fn save_state(&self, state: &mut StateWriter) {
+        state.put_bytes(&self.bytes);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SnapshotError> {
+        load_bytes(&mut self.bytes, state, "ROM")
+    }
+}
+
+struct Region {
+    range: RangeInclusive<u16>,
+    device: Box<dyn Bus>,
+}
+
+/// A bus built from devices mapped at address ranges. Each device sees addresses relative to
+/// the start of its range. Later mappings take priority over earlier overlapping ones, and
+/// unmapped addresses read 0 and ignore writes.
+#[derive(Default)]
+pub struct MemoryMap {
+    regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        MemoryMap::default()
+    }
+
+    /// The standard machine: RAM over the whole address space with `console` mapped over the
+    /// I/O region and a timer on interrupt line 0 mapped over its registers.
+    pub fn standard(console: Console) -> MemoryMap {
+        let mut map: MemoryMap = MemoryMap::new();
+        map.map(0..=(ADDRESS_SPACE - 1) as u16, Box::new(Ram::default()));
+        map.map(console::IO_START..=console::IO_END, Box::new(console));
+        map.map(timer::TIMER_START..=timer::TIMER_END, Box::new(Timer::new(0)));
+        map
+    }
+
+    pub fn map(&mut self, range: RangeInclusive<u16>, device: Box<dyn Bus>) {
+        self.regions.push(Region { range, device });
+    }
+
+    fn region(&self, address: u16) -> Option<(&Region, u16)> {
+        self.regions
+            .iter()
+            .rev()
+            .find(|region| region.range.contains(&address))
+            .map(|region| (region, address - region.range.start()))
+    }
+
+    fn region_mut(&mut self, address: u16) -> Option<(&mut Region, u16)> {
+        self.regions
+            .iter_mut()
+            .rev()
+            .find(|region| region.range.contains(&address))
+            .map(|region| {
+                let offset: u16 = address - region.range.start();
+                (region, offset)
+            })
+    }
+}
+
+impl Bus for MemoryMap {
+    fn read_u8(&mut self, address: u16) -> io::Result<u8> {
+        match self.region_mut(address) {
+            Some((region, offset)) => region.device.read_u8(offset),
+            None => Ok(0),
+        }
+    }
+
+    fn write_u8(&mut self, address: u16, value: u8) -> io::Result<()> {
+        match self.region_mut(address) {
+            Some((region, offset)) => region.device.write_u8(offset, value),
+            None => Ok(()),
+        }
+    }
+
+    fn peek_u8(&self, address: u16) -> u8 {
+        self.region(address).map_or(0, |(region, offset)| region.device.peek_u8(offset))
+    }
+
+    fn poke_u8(&mut self, address: u16, value: u8) {
+        if let Some((region, offset)) = self.region_mut(address) {
+            region.device.poke_u8(offset, value);
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        for region in self.regions.iter_mut() {
+            region.device.tick(cycles);
+        }
+    }
+
+    fn take_interrupts(&mut self) -> u8 {
+        self.regions.iter_mut().fold(0, |lines, region| lines | region.device.take_interrupts())
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.put_u16(self.regions.len() as u16);
+        for region in &self.regions {
+            region.device.save_state(state);
+        }
+    }
+
+    // the map must have been built the same way as the one that was saved
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SnapshotError> {
+        let regions: u16 = state.get_u16()?;
+        if regions as usize != self.regions.len() {
+            return Err(SnapshotError::Mismatch(format!("{} mapped regions, expected {}", regions, self.regions.len())));
+        }
+        for region in self.regions.iter_mut() {
+            region.device.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ram() {
+        let mut ram: Ram = Ram::new(4);
+        ram.write_u8(1, 0xAB).unwrap();
+        ram.write_u8(2, 0xCD).unwrap();
+        ram.write_u8(9, 0xFF).unwrap();
+
+        assert_eq!(ram.read_u16(1).unwrap(), 0xABCD);
+        assert_eq!(ram.read_u8(9).unwrap(), 0);
+        assert_eq!(ram.peek_range(0, 4), vec![0, 0xAB, 0xCD, 0]);
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut map: MemoryMap = MemoryMap::new();
+        map.map(0x000..=0x0FF, Box::new(Ram::new(0x100)));
+        map.map(0x100..=0x1FF, Box::new(Rom::new(vec![1, 2, 3])));
+        map.map(0x080..=0x08F, Box::new(Ram::new(0x10)));
+
+        // the later RAM mapping shadows the first one
+        map.write_u8(0x081, 7).unwrap();
+        assert_eq!(map.read_u8(0x081).unwrap(), 7);
+        map.map(0x080..=0x08F, Box::new(Ram::new(0x10)));
+        assert_eq!(map.read_u8(0x081).unwrap(), 0);
+
+        // ROM ignores program writes but can be poked
+        map.write_u8(0x101, 9).unwrap();
+        assert_eq!(map.read_u16(0x100).unwrap(), 0x0102);
+        map.poke_u8(0x101, 9);
+        assert_eq!(map.peek_u8(0x101), 9);
+
+        // unmapped
+        map.write_u8(0x300, 1).unwrap();
+        assert_eq!(map.read_u8(0x300).unwrap(), 0);
+    }
+}
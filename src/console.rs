@@ -0,0 +1,153 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::bus::Bus;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+/*
+    memory-mapped I/O region, 0xF00 - 0xF0F:
+    - 0xF00: console data, writing a byte emits it to the console output
+    - 0xF01: console status, reads 1 while an input byte is available and 0 once input is exhausted
+    - 0xF02: console input, reading consumes the next input byte (0 if there is none)
+    - 0xF04 - 0xF08: timer, see timer.rs
+    - everything else in the region is reserved: writes are ignored and reads return 0
+*/
+
+/// First address of the I/O region, where `MemoryMap::standard` maps the console.
+pub const IO_START: u16 = 0xF00;
+/// Last address of the I/O region.
+pub const IO_END: u16 = 0xF0F;
+/// Console data port.
+pub const CONSOLE_DATA: u16 = 0xF00;
+/// Console status port.
+pub const CONSOLE_STATUS: u16 = 0xF01;
+/// Console input port.
+pub const CONSOLE_INPUT: u16 = 0xF02;
+
+/// Host side of the console device.
+pub struct Console {
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+    pending: Option<u8>, // byte read ahead by a status check
+}
+
+impl Console {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> Console {
+        Console { input, output, pending: None }
+    }
+
+    pub fn stdio() -> Console {
+        Console::new(Box::new(io::stdin()), Box::new(io::stdout()))
+    }
+
+    /// Whether an input byte is available. Blocks until the source produces a byte or ends.
+    pub fn status(&mut self) -> io::Result<bool> {
+        if self.pending.is_none() {
+            let mut byte: [u8; 1] = [0];
+            self.pending = match self.input.read(&mut byte) {
+                Ok(0) => None,
+                Ok(_) => Some(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return self.status(),
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(self.pending.is_some())
+    }
+
+    /// Consumes the next input byte, or returns 0 once input is exhausted.
+    pub fn read(&mut self) -> io::Result<u8> {
+        self.status()?;
+        Ok(self.pending.take().unwrap_or(0))
+    }
+
+    /// Emits one byte, flushing immediately so output interleaves with the host's.
+    pub fn write(&mut self, byte: u8) -> io::Result<()> {
+        self.output.write_all(&[byte])?;
+        self.output.flush()
+    }
+}
+
+impl Bus for Console {
+    fn read_u8(&mut self, address: u16) -> io::Result<u8> {
+        match address + IO_START {
+            CONSOLE_STATUS => self.status().map(|available| available as u8),
+            CONSOLE_INPUT => self.read(),
+            _ => Ok(0),
+        }
+    }
+
+    fn write_u8(&mut self, address: u16, value: u8) -> io::Result<()> {
+        match address + IO_START {
+            CONSOLE_DATA => self.write(value),
+            _ => Ok(()),
+        }
+    }
+
+    // only reports input that a status read has already fetched
+    fn peek_u8(&self, address: u16) -> u8 {
+        match address + IO_START {
+            CONSOLE_STATUS => self.pending.is_some() as u8,
+            CONSOLE_INPUT => self.pending.unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn poke_u8(&mut self, _address: u16, _value: u8) {}
+
+    // only the read-ahead byte; the host streams themselves are not part of a save state
+    fn save_state(&self, state: &mut StateWriter) {
+        state.put_bool(self.pending.is_some());
+        state.put_u8(self.pending.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SnapshotError> {
+        let has_pending: bool = state.get_bool()?;
+        let pending: u8 = state.get_u8()?;
+        self.pending = if has_pending { Some(pending) } else { None };
+        Ok(())
+    }
+}
+
+/// An in-memory sink whose clones share one buffer, so a test can keep a handle to what the
+/// guest program printed.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> SharedBuffer {
+        SharedBuffer::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input() {
+        let mut console: Console = Console::new(Box::new(io::Cursor::new(b"ab".to_vec())), Box::new(io::sink()));
+
+        assert!(console.status().unwrap());
+        assert!(console.status().unwrap());
+        assert_eq!(console.read().unwrap(), b'a');
+        assert_eq!(console.read().unwrap(), b'b');
+        assert!(!console.status().unwrap());
+        assert_eq!(console.read().unwrap(), 0);
+    }
+}
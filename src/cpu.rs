@@ -1,294 +1,2259 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+
+use crate::bus::{Bus, MemoryMap, ADDRESS_SPACE};
+use crate::console::Console;
+use crate::expression::Expression;
+use crate::instruction::{Flag, Instruction};
+use crate::snapshot::{self, SnapshotError, StateReader, StateWriter};
+use crate::timing::CostTable;
+use crate::trace::{TraceRecord, Tracer};
+
+/// Why [`Cpu::run`] stopped without a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    /// The `0000` halt opcode was executed.
+    Halt,
+    /// The next step would exceed the [`Budget`]; it was not executed.
+    BudgetExhausted,
+    /// The program counter reached a breakpoint; the instruction there was not executed.
+    Breakpoint,
+    /// The last instruction accessed a watched register or memory byte.
+    Watchpoint { target: WatchTarget, access: Access },
+    /// The last step turned the condition with this id from false to true.
+    Condition(usize),
+}
+
+/// Something a watchpoint can watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WatchTarget {
+    Register(u8),
+    Memory(u16),
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchTarget::Register(register) => write!(f, "r{}", register),
+            WatchTarget::Memory(address) => write!(f, "0x{:03x}", address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// How much a program may still execute before [`Cpu::step`] stops it with
+/// [`HaltReason::BudgetExhausted`]. Taking an interrupt costs cycles and gas but is not an
+/// instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Instructions(u64),
+    Cycles(u64),
+    /// Gas as charged by the CPU's gas table.
+    Gas(u64),
+}
+
+/// What a single [`Cpu::step`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// `instruction` at address `pc` was executed.
+    Executed { pc: u16, instruction: Instruction },
+    /// The CPU is halted; the program counter stays on the halting instruction.
+    Halted(HaltReason),
+    /// Interrupt `line` was taken instead of executing an instruction: the interrupted address
+    /// was pushed and execution continues at `handler`.
+    Interrupted { line: u8, handler: u16 },
+}
+
+/// Start of the interrupt vector table: 8 big-endian handler addresses, one per interrupt line.
+pub const VECTOR_TABLE: u16 = 0xFF0;
+/// Number of interrupt lines.
+pub const INTERRUPT_LINES: u8 = 8;
+
+/// A fault raised while executing a program. `pc` is the address of the faulting instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// `call` with all 16 stack slots in use.
+    StackOverflow { pc: u16 },
+    /// `ret` with an empty stack.
+    StackUnderflow { pc: u16 },
+    /// The opcode does not decode to an operation.
+    InvalidOpcode { pc: u16, opcode: u16 },
+    /// The program counter left memory, so no full opcode could be fetched.
+    PcOutOfBounds { pc: u16 },
+    /// `div` or `mod` with a zero divisor.
+    DivideByZero { pc: u16 },
+    /// A load or store addressed memory past 0xFFF.
+    MemoryOutOfBounds { pc: u16, address: u16 },
+    /// The host side of a memory-mapped device failed.
+    Io { pc: u16, address: u16, kind: io::ErrorKind },
+    /// The tracer could not record the instruction at `pc`, which was still executed.
+    Trace { pc: u16, kind: io::ErrorKind },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::StackOverflow { pc } => write!(f, "stack overflow at 0x{:03x}", pc),
+            CpuError::StackUnderflow { pc } => write!(f, "stack underflow at 0x{:03x}", pc),
+            CpuError::InvalidOpcode { pc, opcode } => write!(f, "invalid opcode 0x{:04x} at 0x{:03x}", opcode, pc),
+            CpuError::PcOutOfBounds { pc } => write!(f, "program counter out of bounds at 0x{:03x}", pc),
+            CpuError::DivideByZero { pc } => write!(f, "divide by zero at 0x{:03x}", pc),
+            CpuError::MemoryOutOfBounds { pc, address } => write!(f, "memory access to 0x{:x} out of bounds at 0x{:03x}", address, pc),
+            CpuError::Trace { pc, kind } => write!(f, "tracing failed after 0x{:03x}: {}", pc, io::Error::from(kind)),
+            CpuError::Io { pc, address, kind } => write!(f, "I/O error on 0x{:03x} at 0x{:03x}: {}", address, pc, io::Error::from(kind)),
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+/// A program image that does not fit in memory at the requested origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadError {
+    pub origin: u16,
+    pub len: usize,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "program of {} bytes does not fit in memory at 0x{:03x}", self.len, self.origin)
+    }
+}
+
+impl Error for LoadError {}
+
+/// Status flags, updated by every ALU operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags {
+    /// The result was 0.
+    pub zero: bool,
+    /// The unsigned result did not fit in 8 bits. Always cleared by bitwise operations.
+    pub carry: bool,
+    /// The signed result did not fit in 8 bits. Always cleared by bitwise operations.
+    pub overflow: bool,
+    /// Bit 7 of the result is set.
+    pub negative: bool,
+}
+
+impl Flags {
+    pub fn get(&self, flag: Flag) -> bool {
+        match flag {
+            Flag::Zero => self.zero,
+            Flag::Carry => self.carry,
+            Flag::Overflow => self.overflow,
+            Flag::Negative => self.negative,
+        }
+    }
+
+    // one bit per flag, in `Flag` nibble order
+    fn bits(&self) -> u8 {
+        self.zero as u8 | (self.carry as u8) << 1 | (self.overflow as u8) << 2 | (self.negative as u8) << 3
+    }
+
+    fn from_bits(bits: u8) -> Flags {
+        Flags { zero: bits & 1 != 0, carry: bits & 2 != 0, overflow: bits & 4 != 0, negative: bits & 8 != 0 }
+    }
+}
+
+impl fmt::Display for Flags {
+    /// Formats as `ZCVN`, with `-` for each clear flag.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        write!(f, "{}{}{}{}", flag(self.zero, 'Z'), flag(self.carry, 'C'), flag(self.overflow, 'V'), flag(self.negative, 'N'))
+    }
+}
+
+// machine state from before one step, enough to undo it
+struct Undo {
+    program_counter: usize,
+    registers: [u8; 16],
+    written: u16, // one bit per register the step wrote
+    stack_pointer: usize,
+    stack_slot: Option<u16>, // the slot a call or interrupt entry may have overwritten
+    flags: Flags,
+    index: u16,
+    interrupts_enabled: bool,
+    pending_interrupts: u8,
+    cycles: u64,
+    gas: u64,
+    budget: Option<Budget>,
+    memory: Vec<(u16, u8)>, // old value of every byte written, in write order
+}
+
 #[allow(dead_code)]
+pub struct Cpu<B: Bus = MemoryMap> {
+    registers: [u8; 16], // 16 registers
+    bus: B, // 4 kiB address space of memory and devices
+    program_counter: usize, // which memory address to access
+    stack: [u16; 16],
+    stack_pointer: usize,
+    flags: Flags,
+    index: u16, // address register for loads and stores
+    interrupts_enabled: bool,
+    pending_interrupts: u8, // one bit per interrupt line
+    cycles: u64, // cycles elapsed since reset
+    costs: CostTable,
+    gas: u64, // gas charged since reset
+    gas_costs: CostTable,
+    budget: Option<Budget>, // what is left of the budget
+    tracer: Option<Box<dyn Tracer>>,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeSet<(WatchTarget, Access)>,
+    conditions: BTreeMap<usize, (Expression, bool)>, // with whether each held after the last step
+    next_condition: usize,
+    triggered: Option<HaltReason>, // watchpoint or condition hit by the last step
+    history: VecDeque<Undo>, // most recent step last
+    history_limit: usize, // 0 when not recording
+    memory_undo: Vec<(u16, u8)>, // old values written so far by the current step
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Cpu::new()
+    }
+}
+
+impl Cpu {
+    /// A CPU on the standard memory map, with the console on stdin/stdout.
+    pub fn new() -> Cpu {
+        Cpu::with_bus(MemoryMap::standard(Console::stdio()))
+    }
+}
+
+#[allow(dead_code)]
+impl<B: Bus> Cpu<B> {
+    pub fn with_bus(bus: B) -> Cpu<B> {
+        let registers: [u8; 16] = [0; 16];
+        let stack: [u16; 16] = [0; 16];
+        Cpu {
+            registers,
+            bus,
+            program_counter: 0,
+            stack,
+            stack_pointer: 0,
+            flags: Flags::default(),
+            index: 0,
+            interrupts_enabled: false,
+            pending_interrupts: 0,
+            cycles: 0,
+            costs: CostTable::default(),
+            gas: 0,
+            gas_costs: CostTable::uniform(1),
+            budget: None,
+            tracer: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            conditions: BTreeMap::new(),
+            next_condition: 1,
+            triggered: None,
+            history: VecDeque::new(),
+            history_limit: 0,
+            memory_undo: Vec::new(),
+        }
+    }
+
+    /// Copies `program` into memory starting at `origin`, bypassing device side effects and
+    /// ROM protection. Memory outside the image is untouched.
+    pub fn load_program(&mut self, program: &[u8], origin: u16) -> Result<(), LoadError> {
+        if origin as usize + program.len() > ADDRESS_SPACE {
+            return Err(LoadError { origin, len: program.len() });
+        }
+        for (offset, byte) in program.iter().enumerate() {
+            self.bus.poke_u8(origin + offset as u16, *byte);
+        }
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Runs until the program halts, faults or reaches a breakpoint. `run`, `run_for` and
+    /// `run_for_cycles` never stop on a breakpoint at the address they start from, so resuming
+    /// from a breakpoint makes progress.
+    pub fn run(&mut self) -> Result<HaltReason, CpuError> {
+        let mut first: bool = true;
+        loop {
+            if let Step::Halted(reason) = self.step_or_break(first)? {
+                return Ok(reason);
+            }
+            first = false;
+        }
+    }
+
+    /// Executes at most `instructions` instructions. Returns `None` if the program is still
+    /// running, in which case a later `run`/`run_for` call resumes where this one stopped.
+    pub fn run_for(&mut self, instructions: usize) -> Result<Option<HaltReason>, CpuError> {
+        for count in 0..instructions {
+            if let Step::Halted(reason) = self.step_or_break(count == 0)? {
+                return Ok(Some(reason));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs until at least `cycles` more cycles have elapsed. The last instruction may overshoot
+    /// the target; returns `None` if the program is still running. A step that costs no cycles
+    /// also ends the run, so a zero-cost program cannot spin here forever.
+    pub fn run_for_cycles(&mut self, cycles: u64) -> Result<Option<HaltReason>, CpuError> {
+        let end: u64 = self.cycles.saturating_add(cycles);
+        let mut first: bool = true;
+        while self.cycles < end {
+            let before: u64 = self.cycles;
+            if let Step::Halted(reason) = self.step_or_break(first)? {
+                return Ok(Some(reason));
+            }
+            if self.cycles == before {
+                break;
+            }
+            first = false;
+        }
+        Ok(None)
+    }
+
+    // `step`, but stops on a breakpoint unless this is the first step of a run, and after a
+    // step that triggered a watchpoint or condition
+    fn step_or_break(&mut self, first: bool) -> Result<Step, CpuError> {
+        if !first && self.breakpoints.contains(&(self.program_counter as u16)) {
+            return Ok(Step::Halted(HaltReason::Breakpoint));
+        }
+        let step: Step = self.step()?;
+        match self.triggered {
+            Some(reason) => Ok(Step::Halted(reason)),
+            None => Ok(step),
+        }
+    }
+
+    /// Executes exactly one instruction, or enters the handler of a pending interrupt if
+    /// interrupts are enabled. A halted CPU stays on its halt opcode, so stepping it again
+    /// reports the same halt unless an interrupt wakes it. Breakpoints, watchpoints and
+    /// conditions do not stop a single step; see [`Cpu::get_triggered`].
+    pub fn step(&mut self) -> Result<Step, CpuError> {
+        self.triggered = None;
+        if self.history_limit == 0 {
+            return self.execute();
+        }
+        let mut undo: Undo = Undo {
+            program_counter: self.program_counter,
+            registers: self.registers,
+            written: 0,
+            stack_pointer: self.stack_pointer,
+            stack_slot: self.stack.get(self.stack_pointer).copied(),
+            flags: self.flags,
+            index: self.index,
+            interrupts_enabled: self.interrupts_enabled,
+            pending_interrupts: self.pending_interrupts,
+            cycles: self.cycles,
+            gas: self.gas,
+            budget: self.budget,
+            memory: Vec::new(),
+        };
+        let result: Result<Step, CpuError> = self.execute();
+        undo.memory = mem::take(&mut self.memory_undo);
+        // a register rewritten with its old value still counts as written
+        undo.written = (0..16).filter(|register| undo.registers[*register] != self.registers[*register]).fold(0, |written, register| written | 1 << register);
+        if let Ok(Step::Executed { instruction, .. }) = &result {
+            undo.written |= instruction.register_writes();
+        }
+        // a halt changes nothing, but a fault may have written memory before it was detected
+        if matches!(result, Ok(Step::Executed { .. } | Step::Interrupted { .. })) || undo.written != 0 || !undo.memory.is_empty() {
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(undo);
+        }
+        result
+    }
+
+    fn execute(&mut self) -> Result<Step, CpuError> {
+        if self.interrupts_enabled && self.pending_interrupts != 0 {
+            return self.interrupt();
+        }
+        if self.program_counter >= (ADDRESS_SPACE - 1) {
+            return Err(CpuError::PcOutOfBounds { pc: self.program_counter as u16 });
+        }
+        let pc: u16 = self.program_counter as u16;
+        let current_opcode: u16 = self.bus.read_u16(pc).map_err(|e| self.io_error(pc as usize, e))?;
+        let instruction: Instruction = Instruction::decode(current_opcode);
+        let cycles: u32 = self.costs.get(&instruction);
+        let gas: u32 = self.gas_costs.get(&instruction);
+        if instruction != Instruction::Halt && !self.affordable(1, cycles, gas) {
+            return Ok(Step::Halted(HaltReason::BudgetExhausted));
+        }
+        let registers: [u8; 16] = self.registers;
+
+        match instruction {
+            Instruction::Halt => return Ok(Step::Halted(HaltReason::Halt)),
+            Instruction::Nop => (),
+            Instruction::ReturnFromInterrupt => {
+                self.ret()?;
+                self.interrupts_enabled = true;
+            }
+            Instruction::EnableInterrupts => self.interrupts_enabled = true,
+            Instruction::DisableInterrupts => self.interrupts_enabled = false,
+            Instruction::Add { x, y, z } => self.add_y_x(&x, &y, &z),
+            Instruction::AddImm { x, value, z } => self.add_x(&x, &value, &z),
+            Instruction::Or { x, y, z } => self.or_y_x(&x, &y, &z),
+            Instruction::OrImm { x, value, z } => self.or_x(&x, &value, &z),
+            Instruction::And { x, y, z } => self.and_y_x(&x, &y, &z),
+            Instruction::AndImm { x, value, z } => self.and_x(&x, &value, &z),
+            Instruction::Mov { x, y } => self.mov_y_x(&x, &y),
+            Instruction::MovImm { x, value } => self.mov_x(&x, &value),
+            Instruction::Jump { address } => self.jump(&address),
+            Instruction::Call { address } => self.call(&address)?,
+            Instruction::Ret => self.ret()?,
+            Instruction::SkipEq { x, y } => self.skip_if(self.registers[x as usize] == self.registers[y as usize]),
+            Instruction::SkipNe { x, y } => self.skip_if(self.registers[x as usize] != self.registers[y as usize]),
+            Instruction::SkipEqImm { x, value } => self.skip_if(self.registers[x as usize] == value),
+            Instruction::SkipNeImm { x, value } => self.skip_if(self.registers[x as usize] != value),
+            Instruction::SkipIfSet { flag } => self.skip_if(self.flags.get(flag)),
+            Instruction::SkipIfClear { flag } => self.skip_if(!self.flags.get(flag)),
+            Instruction::Sub { x, y } => self.sub_y_x(&x, &y),
+            Instruction::SubBorrow { x, y } => self.sbc_y_x(&x, &y),
+            Instruction::Xor { x, y } => self.xor_y_x(&x, &y),
+            Instruction::Not { x, y } => self.not_y_x(&x, &y),
+            Instruction::ShiftLeft { x, y } => self.shl_y_x(&x, &y),
+            Instruction::ShiftRight { x, y } => self.shr_y_x(&x, &y),
+            Instruction::RotateLeft { x, y } => self.rol_y_x(&x, &y),
+            Instruction::RotateRight { x, y } => self.ror_y_x(&x, &y),
+            Instruction::Mul { x, y } => self.mul_y_x(&x, &y),
+            Instruction::Div { x, y } => self.div_y_x(&x, &y)?,
+            Instruction::Mod { x, y } => self.mod_y_x(&x, &y)?,
+            Instruction::SetIndex { address } => self.index = address,
+            Instruction::LoadIndexed { x, y } => {
+                let offset: u8 = self.registers[y as usize];
+                self.load(&x, &offset)?
+            }
+            Instruction::StoreIndexed { x, y } => {
+                let offset: u8 = self.registers[y as usize];
+                self.store(&x, &offset)?
+            }
+            Instruction::Load { x } => self.load(&x, &0)?,
+            Instruction::Store { x } => self.store(&x, &0)?,
+            Instruction::LoadBlock { x } => self.load_block(&x)?,
+            Instruction::StoreBlock { x } => self.store_block(&x)?,
+            Instruction::AddIndex { x } => self.index = (self.index + self.registers[x as usize] as u16) & 0xFFF,
+            Instruction::Unknown(opcode) => return Err(CpuError::InvalidOpcode { pc, opcode }),
+        }
+        // reti resumes at the interrupted instruction itself
+        if !matches!(instruction, Instruction::Jump { .. } | Instruction::Call { .. } | Instruction::ReturnFromInterrupt) {
+            self.program_counter += 2; // memory holds u8 but opcode is u16
+        }
+        self.elapse(1, cycles, gas);
+        self.watch_registers(&instruction);
+        self.check_conditions();
+        if let Some(tracer) = self.tracer.as_mut() {
+            let changed: Vec<(u8, u8)> = (0..16_u8)
+                .filter(|register| registers[*register as usize] != self.registers[*register as usize])
+                .map(|register| (register, self.registers[register as usize]))
+                .collect();
+            let record: TraceRecord = TraceRecord { pc, opcode: current_opcode, instruction, changed, stack_pointer: self.stack_pointer as u8 };
+            tracer.record(&record).map_err(|e| CpuError::Trace { pc, kind: e.kind() })?;
+        }
+        Ok(Step::Executed { pc, instruction })
+    }
+
+    /// Latches a request on interrupt `line`. It is taken before the next instruction once
+    /// interrupts are enabled; lower lines win when several are pending.
+    pub fn raise_interrupt(&mut self, line: u8) {
+        assert!(line < INTERRUPT_LINES, "interrupt line {} out of range", line);
+        self.pending_interrupts |= 1 << line;
+    }
+
+    pub fn get_interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+
+    pub fn get_pending_interrupts(&self) -> u8 {
+        self.pending_interrupts
+    }
+
+    /// Serializes the whole machine: registers, flags, stack, interrupt and budget state, the
+    /// cycle and gas counters, and every device on the bus. Cost tables are configuration and
+    /// are not saved.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state: StateWriter = StateWriter::new();
+        for byte in snapshot::MAGIC {
+            state.put_u8(byte);
+        }
+        state.put_u16(snapshot::VERSION);
+        for register in self.registers {
+            state.put_u8(register);
+        }
+        state.put_u16(self.program_counter as u16);
+        for address in self.stack {
+            state.put_u16(address);
+        }
+        state.put_u8(self.stack_pointer as u8);
+        state.put_u8(self.flags.bits());
+        state.put_u16(self.index);
+        state.put_bool(self.interrupts_enabled);
+        state.put_u8(self.pending_interrupts);
+        state.put_u64(self.cycles);
+        state.put_u64(self.gas);
+        let (kind, left): (u8, u64) = match self.budget {
+            None => (0, 0),
+            Some(Budget::Instructions(left)) => (1, left),
+            Some(Budget::Cycles(left)) => (2, left),
+            Some(Budget::Gas(left)) => (3, left),
+        };
+        state.put_u8(kind);
+        state.put_u64(left);
+        self.bus.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    /// Restores a state written by [`Cpu::save_state`] on a CPU whose bus is laid out the same
+    /// way. On error the CPU itself is unchanged, but devices may be partially restored.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let mut state: StateReader = StateReader::new(bytes);
+        let mut magic: [u8; 4] = [0; 4];
+        for byte in magic.iter_mut() {
+            *byte = state.get_u8().map_err(|_| SnapshotError::BadMagic)?;
+        }
+        if magic != snapshot::MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version: u16 = state.get_u16()?;
+        if version != snapshot::VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let mut registers: [u8; 16] = [0; 16];
+        for register in registers.iter_mut() {
+            *register = state.get_u8()?;
+        }
+        let program_counter: u16 = state.get_u16()?;
+        if program_counter as usize >= ADDRESS_SPACE {
+            return Err(SnapshotError::Mismatch(format!("program counter 0x{:x}", program_counter)));
+        }
+        let mut stack: [u16; 16] = [0; 16];
+        for address in stack.iter_mut() {
+            *address = state.get_u16()?;
+        }
+        let stack_pointer: u8 = state.get_u8()?;
+        if stack_pointer as usize > stack.len() {
+            return Err(SnapshotError::Mismatch(format!("stack pointer {}", stack_pointer)));
+        }
+        let flags: Flags = Flags::from_bits(state.get_u8()?);
+        let index: u16 = state.get_u16()?;
+        if index as usize >= ADDRESS_SPACE {
+            return Err(SnapshotError::Mismatch(format!("index 0x{:x}", index)));
+        }
+        let interrupts_enabled: bool = state.get_bool()?;
+        let pending_interrupts: u8 = state.get_u8()?;
+        let cycles: u64 = state.get_u64()?;
+        let gas: u64 = state.get_u64()?;
+        let budget: Option<Budget> = match (state.get_u8()?, state.get_u64()?) {
+            (0, _) => None,
+            (1, left) => Some(Budget::Instructions(left)),
+            (2, left) => Some(Budget::Cycles(left)),
+            (3, left) => Some(Budget::Gas(left)),
+            (kind, _) => return Err(SnapshotError::Mismatch(format!("budget kind {}", kind))),
+        };
+        self.bus.load_state(&mut state)?;
+        if !state.is_empty() {
+            return Err(SnapshotError::TrailingBytes);
+        }
+
+        self.registers = registers;
+        self.program_counter = program_counter as usize;
+        self.stack = stack;
+        self.stack_pointer = stack_pointer as usize;
+        self.flags = flags;
+        self.index = index;
+        self.interrupts_enabled = interrupts_enabled;
+        self.pending_interrupts = pending_interrupts;
+        self.cycles = cycles;
+        self.gas = gas;
+        self.budget = budget;
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Sends a [`TraceRecord`] for every executed instruction to `tracer`, or stops tracing.
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = tracer;
+    }
+
+    /// Cycles elapsed since the CPU was created, as charged by its [`CostTable`].
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn get_cost_table(&self) -> &CostTable {
+        &self.costs
+    }
+
+    pub fn set_cost_table(&mut self, costs: CostTable) {
+        self.costs = costs;
+    }
+
+    /// Gas charged since the CPU was created. Every instruction costs 1 gas unless a gas table
+    /// is set.
+    pub fn get_gas(&self) -> u64 {
+        self.gas
+    }
+
+    pub fn get_gas_table(&self) -> &CostTable {
+        &self.gas_costs
+    }
+
+    pub fn set_gas_table(&mut self, gas_costs: CostTable) {
+        self.gas_costs = gas_costs;
+    }
+
+    /// What is left of the budget, or `None` if execution is unlimited.
+    pub fn get_budget(&self) -> Option<Budget> {
+        self.budget
+    }
+
+    /// Limits further execution to `budget`. Setting a new budget replaces what was left of the
+    /// old one, so a CPU stopped with [`HaltReason::BudgetExhausted`] resumes where it stopped.
+    pub fn set_budget(&mut self, budget: Option<Budget>) {
+        self.budget = budget;
+    }
+
+    /// Makes `run` and friends stop before executing the instruction at `address`. Returns
+    /// `false` if there already was a breakpoint there. Breakpoints are not part of save states.
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Returns `false` if there was no breakpoint at `address`.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn get_breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    /// Makes `run` and friends stop after an instruction makes `access` to `target`. Instruction
+    /// fetches and interrupt vector reads are not watched. Returns `false` if the watchpoint
+    /// already existed. Watchpoints are not part of save states.
+    pub fn add_watchpoint(&mut self, target: WatchTarget, access: Access) -> bool {
+        self.watchpoints.insert((target, access))
+    }
+
+    /// Returns `false` if there was no such watchpoint.
+    pub fn remove_watchpoint(&mut self, target: WatchTarget, access: Access) -> bool {
+        self.watchpoints.remove(&(target, access))
+    }
+
+    pub fn get_watchpoints(&self) -> &BTreeSet<(WatchTarget, Access)> {
+        &self.watchpoints
+    }
+
+    /// Makes `run` and friends stop after a step that turns `condition` from false to true,
+    /// and returns the id reported in [`HaltReason::Condition`]. Conditions are not part of
+    /// save states.
+    pub fn add_condition(&mut self, condition: Expression) -> usize {
+        let id: usize = self.next_condition;
+        self.next_condition += 1;
+        let holds: bool = condition.is_true(self);
+        self.conditions.insert(id, (condition, holds));
+        id
+    }
+
+    /// Returns `false` if there was no condition with `id`.
+    pub fn remove_condition(&mut self, id: usize) -> bool {
+        self.conditions.remove(&id).is_some()
+    }
+
+    pub fn get_conditions(&self) -> impl Iterator<Item = (usize, &Expression)> {
+        self.conditions.iter().map(|(id, (condition, _))| (*id, condition))
+    }
+
+    /// The watchpoint or condition the last step triggered, if any.
+    pub fn get_triggered(&self) -> Option<HaltReason> {
+        self.triggered
+    }
+
+    /// Records how to undo each of the next `limit` steps, forgetting older ones, so they can be
+    /// reversed with [`Cpu::reverse_step`] and [`Cpu::reverse_continue`]. 0, the default, stops
+    /// recording and forgets the history. Only memory bytes are rewound on the bus: device side
+    /// effects such as console output and timer ticks are not undone. The history is not part of
+    /// save states and is forgotten when one is loaded.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn get_history_limit(&self) -> usize {
+        self.history_limit
+    }
+
+    /// Number of recorded steps that can be undone.
+    pub fn get_history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Undoes the most recent recorded step. Returns `false` if there is nothing left to undo.
+    pub fn reverse_step(&mut self) -> bool {
+        match self.history.pop_back() {
+            Some(undo) => {
+                self.undo(undo);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Undoes steps until one that wrote `target`, leaving pc on the instruction that made the
+    /// write, or until pc reaches a breakpoint. Returns `None` if the history ran out first.
+    pub fn reverse_continue(&mut self, target: Option<WatchTarget>) -> Option<HaltReason> {
+        while let Some(undo) = self.history.pop_back() {
+            let wrote: bool = match target {
+                Some(WatchTarget::Register(register)) => undo.written & (1 << register) != 0,
+                Some(WatchTarget::Memory(address)) => undo.memory.iter().any(|(written, _)| *written == address),
+                None => false,
+            };
+            self.undo(undo);
+            match target {
+                Some(target) if wrote => return Some(HaltReason::Watchpoint { target, access: Access::Write }),
+                _ if self.breakpoints.contains(&(self.program_counter as u16)) => return Some(HaltReason::Breakpoint),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    fn undo(&mut self, undo: Undo) {
+        for (address, value) in undo.memory.iter().rev() {
+            self.bus.poke_u8(*address, *value);
+        }
+        if let Some(slot) = undo.stack_slot {
+            self.stack[undo.stack_pointer] = slot;
+        }
+        self.program_counter = undo.program_counter;
+        self.registers = undo.registers;
+        self.stack_pointer = undo.stack_pointer;
+        self.flags = undo.flags;
+        self.index = undo.index;
+        self.interrupts_enabled = undo.interrupts_enabled;
+        self.pending_interrupts = undo.pending_interrupts;
+        self.cycles = undo.cycles;
+        self.gas = undo.gas;
+        self.budget = undo.budget;
+    }
+
+    pub fn get_value_at_register(&self, register_num: u8) -> u8 {
+        self.registers[register_num as usize]
+    }
+
+    pub fn set_value_at_register(&mut self, register_num: u8, value: u8) {
+        self.registers[register_num as usize] = value;
+    }
+
+    pub fn get_flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn get_index(&self) -> u16 {
+        self.index
+    }
+
+    /// Sets the index register, masked to the 12-bit address space like `ldi`.
+    pub fn set_index(&mut self, address: u16) {
+        self.index = address & 0xFFF;
+    }
+
+    /// Return addresses currently on the stack, oldest first.
+    pub fn get_stack(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer]
+    }
+
+    pub fn get_bus(&self) -> &B {
+        &self.bus
+    }
+
+    pub fn get_bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    pub fn get_program_counter(&self) -> u16 {
+        self.program_counter as u16
+    }
+
+    pub fn set_program_counter(&mut self, address: u16) {
+        self.program_counter = address as usize;
+    }
+}
+
+impl<B: Bus> Cpu<B> {
+    fn add_y_x(&mut self, register_x: &u8, register_y: &u8, register_z: &u8) {
+        self.registers[*register_z as usize] = self.add(self.registers[*register_x as usize], self.registers[*register_y as usize]);
+    }
+
+    fn add_x(&mut self, register_x: &u8, val: &u8, register_z: &u8) {
+        self.registers[*register_z as usize] = self.add(self.registers[*register_x as usize], *val);
+    }
+
+    fn or_y_x(&mut self, register_x: &u8, register_y: &u8, register_z: &u8) {
+        self.registers[*register_z as usize] = self.logic(self.registers[*register_x as usize] | self.registers[*register_y as usize]);
+    }
+
+    fn or_x(&mut self, register_x: &u8, val: &u8, register_z: &u8) {
+        self.registers[*register_z as usize] = self.logic(self.registers[*register_x as usize] | val);
+    }
+
+    fn and_y_x(&mut self, register_x: &u8, register_y: &u8, register_z: &u8) {
+        self.registers[*register_z as usize] = self.logic(self.registers[*register_x as usize] & self.registers[*register_y as usize]);
+    }
+
+    fn and_x(&mut self, register_x: &u8, val: &u8, register_z: &u8) {
+        self.registers[*register_z as usize] = self.logic(self.registers[*register_x as usize] & val);
+    }
+
+    fn mov_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        self.registers[*register_x as usize] = self.registers[*register_y as usize];
+    }
+
+    fn mov_x(&mut self, register_x: &u8, val: &u8) {
+        self.registers[*register_x as usize] = *val;
+    }
+
+    fn sub_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        self.registers[*register_x as usize] = self.sub(self.registers[*register_x as usize], self.registers[*register_y as usize], false);
+    }
+
+    fn sbc_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        let borrow: bool = self.flags.carry;
+        self.registers[*register_x as usize] = self.sub(self.registers[*register_x as usize], self.registers[*register_y as usize], borrow);
+    }
+
+    fn xor_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        self.registers[*register_x as usize] = self.logic(self.registers[*register_x as usize] ^ self.registers[*register_y as usize]);
+    }
+
+    fn not_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        self.registers[*register_x as usize] = self.logic(!self.registers[*register_y as usize]);
+    }
+
+    fn shl_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        let value: u8 = self.registers[*register_y as usize];
+        self.registers[*register_x as usize] = self.shift(value << 1, value & 0x80 != 0);
+    }
+
+    fn shr_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        let value: u8 = self.registers[*register_y as usize];
+        self.registers[*register_x as usize] = self.shift(value >> 1, value & 0x01 != 0);
+    }
+
+    fn rol_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        let value: u8 = self.registers[*register_y as usize];
+        self.registers[*register_x as usize] = self.shift(value.rotate_left(1), value & 0x80 != 0);
+    }
+
+    fn ror_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        let value: u8 = self.registers[*register_y as usize];
+        self.registers[*register_x as usize] = self.shift(value.rotate_right(1), value & 0x01 != 0);
+    }
+
+    // carry is set when the product needs the high byte
+    fn mul_y_x(&mut self, register_x: &u8, register_y: &u8) {
+        let product: u16 = self.registers[*register_x as usize] as u16 * self.registers[*register_y as usize] as u16;
+        let [high, low] = product.to_be_bytes();
+        self.registers[*register_x as usize] = low;
+        self.registers[*register_y as usize] = high;
+        self.flags = Flags { zero: product == 0, carry: high != 0, overflow: false, negative: low & 0x80 != 0 };
+    }
+
+    fn div_y_x(&mut self, register_x: &u8, register_y: &u8) -> Result<(), CpuError> {
+        let divisor: u8 = self.divisor(register_y)?;
+        self.registers[*register_x as usize] = self.logic(self.registers[*register_x as usize] / divisor);
+        Ok(())
+    }
+
+    fn mod_y_x(&mut self, register_x: &u8, register_y: &u8) -> Result<(), CpuError> {
+        let divisor: u8 = self.divisor(register_y)?;
+        self.registers[*register_x as usize] = self.logic(self.registers[*register_x as usize] % divisor);
+        Ok(())
+    }
+
+    fn divisor(&self, register_y: &u8) -> Result<u8, CpuError> {
+        match self.registers[*register_y as usize] {
+            0 => Err(CpuError::DivideByZero { pc: self.program_counter as u16 }),
+            divisor => Ok(divisor),
+        }
+    }
+
+    // wrapping add that updates all flags
+    fn add(&mut self, a: u8, b: u8) -> u8 {
+        let (result, carry) = a.overflowing_add(b);
+        self.flags = Flags {
+            zero: result == 0,
+            carry,
+            overflow: (a as i8).overflowing_add(b as i8).1,
+            negative: result & 0x80 != 0,
+        };
+        result
+    }
+
+    // wrapping a - b - borrow; carry is set when the subtraction borrows
+    fn sub(&mut self, a: u8, b: u8, borrow: bool) -> u8 {
+        let wide: i16 = a as i16 - b as i16 - borrow as i16;
+        let signed: i16 = a as i8 as i16 - b as i8 as i16 - borrow as i16;
+        let result: u8 = wide as u8;
+        self.flags = Flags {
+            zero: result == 0,
+            carry: wide < 0,
+            overflow: !(i8::MIN as i16..=i8::MAX as i16).contains(&signed),
+            negative: result & 0x80 != 0,
+        };
+        result
+    }
+
+    // shifts and rotates put the bit shifted out into carry
+    fn shift(&mut self, result: u8, carry: bool) -> u8 {
+        self.flags = Flags { zero: result == 0, carry, overflow: false, negative: result & 0x80 != 0 };
+        result
+    }
+
+    // bitwise results only set zero and negative
+    fn logic(&mut self, result: u8) -> u8 {
+        self.flags = Flags { zero: result == 0, carry: false, overflow: false, negative: result & 0x80 != 0 };
+        result
+    }
+
+    // address I + offset, checked against the end of memory
+    fn memory_address(&self, offset: u16) -> Result<usize, CpuError> {
+        let address: u16 = self.index + offset;
+        if address as usize >= ADDRESS_SPACE {
+            return Err(CpuError::MemoryOutOfBounds { pc: self.program_counter as u16, address });
+        }
+        Ok(address as usize)
+    }
+
+    fn read_memory(&mut self, address: usize) -> Result<u8, CpuError> {
+        self.watch(WatchTarget::Memory(address as u16), Access::Read);
+        self.bus.read_u8(address as u16).map_err(|e| self.io_error(address, e))
+    }
+
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), CpuError> {
+        self.watch(WatchTarget::Memory(address as u16), Access::Write);
+        if self.history_limit != 0 {
+            self.memory_undo.push((address as u16, self.bus.peek_u8(address as u16)));
+        }
+        self.bus.write_u8(address as u16, value).map_err(|e| self.io_error(address, e))
+    }
+
+    // the first watched access of a step is the one reported
+    fn watch(&mut self, target: WatchTarget, access: Access) {
+        if self.triggered.is_none() && self.watchpoints.contains(&(target, access)) {
+            self.triggered = Some(HaltReason::Watchpoint { target, access });
+        }
+    }
+
+    fn watch_registers(&mut self, instruction: &Instruction) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        let (reads, writes): (u16, u16) = (instruction.register_reads(), instruction.register_writes());
+        for register in 0..16 {
+            if reads & (1 << register) != 0 {
+                self.watch(WatchTarget::Register(register), Access::Read);
+            }
+            if writes & (1 << register) != 0 {
+                self.watch(WatchTarget::Register(register), Access::Write);
+            }
+        }
+    }
+
+    fn check_conditions(&mut self) {
+        if self.conditions.is_empty() {
+            return;
+        }
+        let now: Vec<(usize, bool)> = self.conditions.iter().map(|(id, (condition, _))| (*id, condition.is_true(self))).collect();
+        for (id, holds) in now {
+            let held: &mut bool = &mut self.conditions.get_mut(&id).unwrap().1;
+            if holds && !*held && self.triggered.is_none() {
+                self.triggered = Some(HaltReason::Condition(id));
+            }
+            *held = holds;
+        }
+    }
+
+    fn affordable(&self, instructions: u64, cycles: u32, gas: u32) -> bool {
+        match self.budget {
+            None => true,
+            Some(Budget::Instructions(left)) => left >= instructions,
+            Some(Budget::Cycles(left)) => left >= cycles as u64,
+            Some(Budget::Gas(left)) => left >= gas as u64,
+        }
+    }
+
+    // advances the clock and the devices on the bus, and charges the budget
+    fn elapse(&mut self, instructions: u64, cycles: u32, gas: u32) {
+        self.budget = match self.budget {
+            None => None,
+            Some(Budget::Instructions(left)) => Some(Budget::Instructions(left - instructions)),
+            Some(Budget::Cycles(left)) => Some(Budget::Cycles(left - cycles as u64)),
+            Some(Budget::Gas(left)) => Some(Budget::Gas(left - gas as u64)),
+        };
+        self.gas += gas as u64;
+        self.cycles += cycles as u64;
+        self.bus.tick(cycles as u64);
+        self.pending_interrupts |= self.bus.take_interrupts();
+    }
+
+    fn io_error(&self, address: usize, error: io::Error) -> CpuError {
+        CpuError::Io { pc: self.program_counter as u16, address: address as u16, kind: error.kind() }
+    }
+
+    fn load(&mut self, register_x: &u8, offset: &u8) -> Result<(), CpuError> {
+        let address: usize = self.memory_address(*offset as u16)?;
+        self.registers[*register_x as usize] = self.read_memory(address)?;
+        Ok(())
+    }
+
+    fn store(&mut self, register_x: &u8, offset: &u8) -> Result<(), CpuError> {
+        let address: usize = self.memory_address(*offset as u16)?;
+        self.write_memory(address, self.registers[*register_x as usize])
+    }
+
+    fn load_block(&mut self, register_x: &u8) -> Result<(), CpuError> {
+        self.memory_address(*register_x as u16)?;
+        for register in 0..=*register_x {
+            self.load(&register, &register)?;
+        }
+        Ok(())
+    }
+
+    fn store_block(&mut self, register_x: &u8) -> Result<(), CpuError> {
+        self.memory_address(*register_x as u16)?;
+        for register in 0..=*register_x {
+            self.store(&register, &register)?;
+        }
+        Ok(())
+    }
+
+    // the caller's `+= 2` then moves past the skipped instruction
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.program_counter += 2;
+        }
+    }
+
+    fn jump(&mut self, address: &u16) {
+        self.program_counter = *address as usize;
+    }
+
+    fn call(&mut self, address: &u16) -> Result<(), CpuError> {
+        if self.stack_pointer == self.stack.len() {
+            return Err(CpuError::StackOverflow { pc: self.program_counter as u16 });
+        }
+        self.stack[self.stack_pointer] = self.program_counter as u16;
+        self.stack_pointer += 1;
+
+        self.jump(address);
+        Ok(())
+    }
+
+    // push the address about to execute and jump through the vector table with interrupts off
+    fn interrupt(&mut self) -> Result<Step, CpuError> {
+        let line: u8 = self.pending_interrupts.trailing_zeros() as u8;
+        let cycles: u32 = self.costs.get_interrupt();
+        let gas: u32 = self.gas_costs.get_interrupt();
+        if !self.affordable(0, cycles, gas) {
+            return Ok(Step::Halted(HaltReason::BudgetExhausted));
+        }
+        if self.stack_pointer == self.stack.len() {
+            return Err(CpuError::StackOverflow { pc: self.program_counter as u16 });
+        }
+        let vector: u16 = VECTOR_TABLE + 2 * line as u16;
+        let handler: u16 = self.bus.read_u16(vector).map_err(|e| self.io_error(vector as usize, e))? & 0xFFF;
+        self.stack[self.stack_pointer] = self.program_counter as u16;
+        self.stack_pointer += 1;
+        self.pending_interrupts &= !(1 << line);
+        self.interrupts_enabled = false;
+        self.program_counter = handler as usize;
+        self.elapse(0, cycles, gas);
+        self.check_conditions();
+        Ok(Step::Interrupted { line, handler })
+    }
+
+    fn ret(&mut self) -> Result<(), CpuError> {
+        if self.stack_pointer == 0 {
+            return Err(CpuError::StackUnderflow { pc: self.program_counter as u16 });
+        }
+        self.stack_pointer -= 1;
+        self.program_counter = self.stack[self.stack_pointer] as usize;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Ram, Rom};
+    use crate::console::SharedBuffer;
+    use crate::expression::Expression;
+    use crate::trace::TextTracer;
+
+    #[test]
+    fn test_add_y_x() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 3;
+        cpu.registers[1] = 4;
+        cpu.bus.poke_u8(0, 0x10);
+        cpu.bus.poke_u8(1, 0x12);
+        cpu.bus.poke_u8(2, 0x00);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 7);
+    }
+
+    #[test]
+    fn test_add_x() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 3;
+        cpu.bus.poke_u8(0, 0x20);
+        cpu.bus.poke_u8(1, 0xF0);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[0], 18);
+    }
+
+    #[test]
+    fn test_or_y_x() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0b0010;
+        cpu.registers[1] = 0b1010;
+        cpu.bus.poke_u8(0, 0x30);
+        cpu.bus.poke_u8(1, 0x12);
+        cpu.bus.poke_u8(2, 0x00);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 0b1010);
+    }
+
+    #[test]
+    fn test_or_x() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0b0010;
+        cpu.bus.poke_u8(0, 0x40);
+        cpu.bus.poke_u8(1, 0xF2);
+        cpu.bus.poke_u8(2, 0x00);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 0b1111);
+    }
+
+    #[test]
+    fn test_and_y_x() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0b0010;
+        cpu.registers[1] = 0b1010;
+        cpu.bus.poke_u8(0, 0x50);
+        cpu.bus.poke_u8(1, 0x12);
+        cpu.bus.poke_u8(2, 0x00);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 0b0010);
+    }
+
+    #[test]
+    fn test_and_x() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0b1010;
+        cpu.bus.poke_u8(0, 0x60);
+        cpu.bus.poke_u8(1, 0xF2);
+        cpu.bus.poke_u8(2, 0x00);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 0b1010);
+    }
+
+    #[test]
+    fn test_mov_y_x() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0b0010;
+        cpu.registers[1] = 0b1010;
+        cpu.bus.poke_u8(0, 0x70);
+        cpu.bus.poke_u8(1, 0x1F);
+        cpu.bus.poke_u8(2, 0x00);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[0], 0b1010);
+    }
+
+    #[test]
+    fn test_mov_x() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0b1010;
+        cpu.bus.poke_u8(0, 0x80);
+        cpu.bus.poke_u8(1, 0xF2);
+        cpu.bus.poke_u8(2, 0x00);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[0], 0b1111);
+    }
+
+    #[test]
+    fn test_jump() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.bus.poke_u8(0, 0x90);
+        cpu.bus.poke_u8(1, 0x26);
+        cpu.bus.poke_u8(0x26, 0x30);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.program_counter, 0x28);
+    }
+
+    #[test]
+    fn test_fn_call_and_ret() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 3;
+        cpu.registers[1] = 4;
+
+        // Call function at address 0x100
+        cpu.bus.poke_u8(0, 0x01);
+        cpu.bus.poke_u8(1, 0x11);
+        cpu.bus.poke_u8(2, 0xA1);
+        cpu.bus.poke_u8(3, 0x00);
+
+        // terminate
+        cpu.bus.poke_u8(4, 0x00);
+        cpu.bus.poke_u8(5, 0x00);
 
-pub struct Cpu {
-    registers: [u8; 16], // 16 registers
-    memory: [u8; 0x1000], // 4 kiB memory
-    program_counter: usize, // which memory address to access
-    stack: [u16; 16],
-    stack_pointer: usize,
-}
+        // fn to add registers 0 and 1 loaded into hex address 0x100 in memory
+        cpu.bus.poke_u8(0x100, 0x10);
+        cpu.bus.poke_u8(0x101, 0x12);
+        cpu.bus.poke_u8(0x102, 0xB0);
+        cpu.bus.poke_u8(0x103, 0x00);
 
-#[allow(dead_code)]
-impl Cpu {
-    pub fn new() -> Cpu {
-        let registers: [u8; 16] = [0; 16];
-        let memory: [u8; 0x1000] = [0; 0x1000];
-        let stack: [u16; 16] = [0; 16];
-        Cpu {
-            registers,
-            memory,
-            program_counter: 0,
-            stack,
-            stack_pointer: 0,
-        }
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 7);
+        assert_eq!(cpu.program_counter,  4);
     }
 
-    pub fn run(&mut self) {
-        /*
-            opcode split into 4 parts:
-            - bits 15-12 represents operation
-                - 0: noop if 0x0111, terminate if 0x0
-                - 1: integer add involving 2 registers
-                - 2: integer add involving 1 register
-                - 3: bitwise OR involing 2 registers
-                - 4: bitwise OR involving 1 register
-                - 5: bitwise AND involving 2 registers
-                - 6: bitwise AND involving 1 register
-                - 7: Mov value at register Y into register X
-                - 8: Mov value into register X
-                - 9: Jump to memory address specified by bits 11-0
-                - 10: Save current address to stack and jump to memory address specified by bits 11-0
-                - 11: Jump to address stored at top of stack
-            - bits 11-8 represent register X
-            - bits 7-4 represent register Y if operation involves 2 registers
-            - bits 7-4 represent value if operation involves 1 register
-            - bits 3-0 represent where to store result (register Z)
-        */
-        let mut current_opcode: u16;
-        while self.program_counter < (0x1000 - 1) {
-            current_opcode = ((self.memory[self.program_counter] as u16) << 8) | (self.memory[self.program_counter + 1] as u16);
-            if current_opcode == 0x0 { 
-                break;
-            }
+    #[test]
+    fn test_invalid_opcode() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.bus.poke_u8(0, 0x01);
+        cpu.bus.poke_u8(1, 0x11);
+        cpu.bus.poke_u8(2, 0x0F);
+        cpu.bus.poke_u8(3, 0xFF);
 
-            // println!("{:04x}", current_opcode);
-            let opcode_parts: (u8, u8, u8, u8) = self.process_opcode(&current_opcode);
-
-            // println!("{:?}", &opcode_parts);
-
-            match opcode_parts {
-                (0, 1, 1, 1) => (),
-                (1, _, _, _) => self.add_y_x(&opcode_parts.1, &opcode_parts.2, &opcode_parts.3),
-                (2, _, _, _) => self.add_x(&opcode_parts.1, &opcode_parts.2, &opcode_parts.3),
-                (3, _, _, _) => self.or_y_x(&opcode_parts.1, &opcode_parts.2, &opcode_parts.3),
-                (4, _, _, _) => self.or_x(&opcode_parts.1, &opcode_parts.2, &opcode_parts.3),
-                (5, _, _, _) => self.and_y_x(&opcode_parts.1, &opcode_parts.2, &opcode_parts.3),
-                (6, _, _, _) => self.and_x(&opcode_parts.1, &opcode_parts.2, &opcode_parts.3),
-                (7, _, _, _) => self.mov_y_x(&opcode_parts.1, &opcode_parts.2),
-                (8, _, _, _) => self.mov_x(&opcode_parts.1, &opcode_parts.2),
-                (9, _, _, _) => {self.jump(&opcode_parts.1, &opcode_parts.2, &opcode_parts.3); continue;},
-                (10, _, _, _) => {self.call(&opcode_parts.1, &opcode_parts.2, &opcode_parts.3); continue;},
-                (11, _, _, _) => self.ret(),
-                (_, _, _, _) => println!("Not implemented"),
-            }
-            self.program_counter += 2; // memory holds u8 but opcode is u16
-        }
+        assert_eq!(cpu.run(), Err(CpuError::InvalidOpcode { pc: 2, opcode: 0x0FFF }));
     }
 
-    pub fn get_value_at_register(&self, register_num: u8) -> u8 {
-        self.registers[register_num as usize]
+    #[test]
+    fn test_stack_overflow() {
+        let mut cpu: Cpu = Cpu::new();
+        // call self forever
+        cpu.bus.poke_u8(0, 0xA0);
+        cpu.bus.poke_u8(1, 0x00);
+
+        assert_eq!(cpu.run(), Err(CpuError::StackOverflow { pc: 0 }));
+        assert_eq!(cpu.stack_pointer, 16);
     }
-}
 
-impl Cpu {
-    fn process_opcode(&self, current_opcode: &u16) -> (u8, u8, u8, u8) {
-        let operation: u8 = (current_opcode >> 12) as u8;
-        let register_x: u8 = ((current_opcode >> 8) & 0xF) as u8;
-        let register_y: u8 = ((current_opcode >> 4) & 0xF) as u8;
-        let register_z: u8 = (current_opcode & 0xF) as u8;
+    #[test]
+    fn test_stack_underflow() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.bus.poke_u8(0, 0xB0);
+        cpu.bus.poke_u8(1, 0x00);
 
-        (operation, register_x, register_y, register_z)
+        assert_eq!(cpu.run(), Err(CpuError::StackUnderflow { pc: 0 }));
     }
 
-    fn add_y_x(&mut self, register_x: &u8, register_y: &u8, register_z: &u8) {
-        self.registers[*register_z as usize] = self.registers[*register_x as usize] + self.registers[*register_y as usize];
+    #[test]
+    fn test_pc_out_of_bounds() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.bus.poke_u8(0, 0x9F);
+        cpu.bus.poke_u8(1, 0xFF);
+
+        assert_eq!(cpu.run(), Err(CpuError::PcOutOfBounds { pc: 0xFFF }));
     }
 
-    fn add_x(&mut self, register_x: &u8, val: &u8, register_z: &u8) {
-        self.registers[*register_z as usize] = self.registers[*register_x as usize] + val;
+    #[test]
+    fn test_add_wraps_and_sets_flags() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0xFF;
+        cpu.bus.poke_u8(0, 0x20);
+        cpu.bus.poke_u8(1, 0x10);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(cpu.get_flags(), Flags { zero: true, carry: true, overflow: false, negative: false });
     }
 
-    fn or_y_x(&mut self, register_x: &u8, register_y: &u8, register_z: &u8) {
-        self.registers[*register_z as usize] = self.registers[*register_x as usize] | self.registers[*register_y as usize];
+    #[test]
+    fn test_add_signed_overflow() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0x7F;
+        cpu.registers[1] = 0x01;
+        cpu.bus.poke_u8(0, 0x10);
+        cpu.bus.poke_u8(1, 0x12);
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 0x80);
+        assert_eq!(cpu.get_flags(), Flags { zero: false, carry: false, overflow: true, negative: true });
+        assert_eq!(cpu.get_flags().to_string(), "--VN");
     }
 
-    fn or_x(&mut self, register_x: &u8, val: &u8, register_z: &u8) {
-        self.registers[*register_z as usize] = self.registers[*register_x as usize] | val;
+    #[test]
+    fn test_logic_clears_carry() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 0xFF;
+        cpu.registers[1] = 0xF0;
+        // r0 + 1 -> r2 sets carry, then r1 & 0xF -> r3 is zero
+        cpu.bus.poke_u8(0, 0x20);
+        cpu.bus.poke_u8(1, 0x12);
+        cpu.bus.poke_u8(2, 0x61);
+        cpu.bus.poke_u8(3, 0xF3);
+
+        cpu.run_for(1).unwrap();
+        assert!(cpu.get_flags().carry);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_flags(), Flags { zero: true, carry: false, overflow: false, negative: false });
     }
 
-    fn and_y_x(&mut self, register_x: &u8, register_y: &u8, register_z: &u8) {
-        self.registers[*register_z as usize] = self.registers[*register_x as usize] & self.registers[*register_y as usize];
+    #[test]
+    fn test_step() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 3;
+        cpu.registers[1] = 4;
+        cpu.bus.poke_u8(0, 0x10);
+        cpu.bus.poke_u8(1, 0x12);
+        cpu.bus.poke_u8(2, 0x90);
+        cpu.bus.poke_u8(3, 0x10);
+
+        assert_eq!(cpu.step(), Ok(Step::Executed { pc: 0, instruction: Instruction::Add { x: 0, y: 1, z: 2 } }));
+        assert_eq!(cpu.registers[2], 7);
+        assert_eq!(cpu.get_program_counter(), 2);
+
+        assert_eq!(cpu.step(), Ok(Step::Executed { pc: 2, instruction: Instruction::Jump { address: 0x10 } }));
+        assert_eq!(cpu.get_program_counter(), 0x10);
+
+        assert_eq!(cpu.step(), Ok(Step::Halted(HaltReason::Halt)));
+        assert_eq!(cpu.step(), Ok(Step::Halted(HaltReason::Halt)));
+        assert_eq!(cpu.get_program_counter(), 0x10);
     }
 
-    fn and_x(&mut self, register_x: &u8, val: &u8, register_z: &u8) {
-        self.registers[*register_z as usize] = self.registers[*register_x as usize] & val;
+    #[test]
+    fn test_run_for_resumes() {
+        let mut cpu: Cpu = Cpu::new();
+        // r0 += 1 three times, then halt
+        for address in [0_u16, 2, 4] {
+            cpu.bus.poke_u8(address, 0x20);
+            cpu.bus.poke_u8(address + 1, 0x10);
+        }
+
+        assert_eq!(cpu.run_for(2), Ok(None));
+        assert_eq!(cpu.registers[0], 2);
+        assert_eq!(cpu.get_program_counter(), 4);
+
+        assert_eq!(cpu.run_for(5), Ok(Some(HaltReason::Halt)));
+        assert_eq!(cpu.registers[0], 3);
     }
 
-    fn mov_y_x(&mut self, register_x: &u8, register_y: &u8) {
-        self.registers[*register_x as usize] = self.registers[*register_y as usize];
+    #[test]
+    fn test_load_program() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[0] = 3;
+        cpu.registers[1] = 4;
+
+        cpu.load_program(&[0x10, 0x12, 0x00, 0x00], 0x200).unwrap();
+        cpu.set_program_counter(0x200);
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 7);
+        assert_eq!(cpu.get_program_counter(), 0x202);
     }
 
-    fn mov_x(&mut self, register_x: &u8, val: &u8) {
-        self.registers[*register_x as usize] = *val;
+    #[test]
+    fn test_load_program_out_of_bounds() {
+        let mut cpu: Cpu = Cpu::new();
+
+        assert!(cpu.load_program(&[0; 0x1000], 0).is_ok());
+        assert_eq!(cpu.load_program(&[0; 4], 0xFFE), Err(LoadError { origin: 0xFFE, len: 4 }));
+        assert_eq!(cpu.load_program(&[0; 2], 0xFFE), Ok(()));
     }
 
-    fn jump(&mut self, address_part_1: &u8, address_part_2: &u8, address_part_3: &u8) {
-        let address: u16 = (((*address_part_1 as u16) << 8) | ((*address_part_2 as u16) << 4)) | (*address_part_3 as u16);
-        self.program_counter = address as usize;
+    #[test]
+    fn test_assembled_program() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 3
+                    movi r1, 4
+                    call fn_add
+                    halt
+            fn_add: add r0, r1 -> r2
+                    ret
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+        assert_eq!(cpu.registers[2], 7);
+        assert_eq!(cpu.get_program_counter(), program.labels["fn_add"] - 2);
     }
 
-    // todo: add stack overflow check
-    fn call(&mut self, address_part_1: &u8, address_part_2: &u8, address_part_3: &u8) {
-        self.stack[self.stack_pointer] = self.program_counter as u16;
-        self.stack_pointer += 1;
+    #[test]
+    fn test_skip_eq_and_ne() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 3
+                    movi r1, 3
+                    se r0, r1
+                    movi r2, 1      ; skipped
+                    sne r0, r1
+                    movi r3, 1
+                    sei r0, 4
+                    movi r4, 1
+                    snei r0, 4
+                    movi r5, 1      ; skipped
+                    halt
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        cpu.run().unwrap();
 
-        self.jump(address_part_1, address_part_2, address_part_3);
+        assert_eq!(cpu.registers[2..6], [0, 1, 1, 0]);
     }
 
-    // todo: add stack underflow check
-    fn ret(&mut self) {
-        self.stack_pointer -= 1;
-        self.program_counter = self.stack[self.stack_pointer] as usize;
+    #[test]
+    fn test_counted_loop() {
+        // add r1 to r2 five times; the loop ends on data, not a fixed jump
+        let program = crate::assembler::assemble(
+            "
+                    movi r1, 7
+            loop:   add r2, r1 -> r2
+                    addi r0, 1 -> r0
+                    snei r0, 5
+                    halt
+                    jmp loop
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[0], 5);
+        assert_eq!(cpu.registers[2], 35);
     }
-}
 
-mod tests {
-    use super::*;
+    #[test]
+    fn test_skip_on_flags() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 0xF
+                    ori r0, 0xF -> r0
+                    addi r0, 0xF -> r1  ; 0xF + 0xF does not carry
+                    sfc c
+                    halt
+            carry:  movi r1, 0
+                    andi r1, 0 -> r1
+                    sfs z
+                    halt
+                    movi r2, 1
+                    halt
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.registers[2], 1);
+        assert_eq!(cpu.get_program_counter(), 0x14);
+    }
 
     #[test]
-    fn test_add_y_x() {
+    fn test_load_and_store() {
+        let program = crate::assembler::assemble(
+            "
+                    ldi table
+                    movi r1, 2
+                    ld r0, [i + r1]     ; r0 = table[2]
+                    ld r2, [i]          ; r2 = table[0]
+                    ldi result
+                    st r0, [i]
+                    st r2, [i + r1]
+                    halt
+            table:  .byte 5, 6, 7
+            result: .byte 0, 0, 0
+            ",
+        )
+        .unwrap();
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 3;
-        cpu.registers[1] = 4;
-        cpu.memory[0] = 0x10;
-        cpu.memory[1] = 0x12;
-        cpu.memory[2] = 0x00;
+        cpu.load_program(&program.bytes, program.origin).unwrap();
 
-        cpu.run();
+        cpu.run().unwrap();
 
-        assert_eq!(cpu.registers[2], 7);
+        let result: u16 = program.labels["result"];
+        assert_eq!(cpu.registers[0], 7);
+        assert_eq!(cpu.registers[2], 5);
+        assert_eq!(cpu.get_index(), result);
+        assert_eq!(cpu.bus.peek_range(result, 3), [7, 0, 5]);
     }
 
     #[test]
-    fn test_add_x() {
+    fn test_block_load_and_store() {
+        let program = crate::assembler::assemble(
+            "
+                    ldi source
+                    ldm r2
+                    movi r3, 3
+                    add i, r3
+                    stm r2
+                    halt
+            source: .byte 1, 2, 3
+            ",
+        )
+        .unwrap();
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 3;
-        cpu.memory[0] = 0x20;
-        cpu.memory[1] = 0xF0;
+        cpu.load_program(&program.bytes, program.origin).unwrap();
 
-        cpu.run();
+        cpu.run().unwrap();
 
-        assert_eq!(cpu.registers[0], 18);
+        let source: u16 = program.labels["source"];
+        assert_eq!(cpu.registers[0..4], [1, 2, 3, 3]);
+        assert_eq!(cpu.bus.peek_range(source, 6), [1, 2, 3, 1, 2, 3]);
     }
 
     #[test]
-    fn test_or_y_x() {
+    fn test_memory_out_of_bounds() {
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 0b0010;
-        cpu.registers[1] = 0b1010;
-        cpu.memory[0] = 0x30;
-        cpu.memory[1] = 0x12;
-        cpu.memory[2] = 0x00;
+        cpu.registers[1] = 1;
+        // ldi 0xFFF, ld r0, [i + r1]
+        cpu.load_program(&[0xEF, 0xFF, 0xF0, 0x10], 0).unwrap();
 
-        cpu.run();
+        assert_eq!(cpu.run(), Err(CpuError::MemoryOutOfBounds { pc: 2, address: 0x1000 }));
+    }
 
-        assert_eq!(cpu.registers[2], 0b1010);
+    #[test]
+    fn test_sub_with_borrow() {
+        // 0x0103 - 0x0005 as two bytes: low byte in r0, high byte in r1
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 3
+                    movi r1, 1
+                    movi r2, 5
+                    sub r0, r2
+                    sbc r1, r3
+                    halt
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        cpu.run_for(4).unwrap();
+        assert_eq!(cpu.registers[0], 0xFE);
+        assert_eq!(cpu.get_flags(), Flags { zero: false, carry: true, overflow: false, negative: true });
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(cpu.get_flags(), Flags { zero: true, carry: false, overflow: false, negative: false });
     }
 
     #[test]
-    fn test_or_x() {
+    fn test_sub_signed_overflow() {
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 0b0010;
-        cpu.memory[0] = 0x40;
-        cpu.memory[1] = 0xF2;
-        cpu.memory[2] = 0x00;
+        cpu.registers[0] = 0x80;
+        cpu.registers[1] = 0x01;
+        cpu.load_program(&[0xC0, 0x10], 0).unwrap();
 
-        cpu.run();
+        cpu.run().unwrap();
 
-        assert_eq!(cpu.registers[2], 0b1111);
+        assert_eq!(cpu.registers[0], 0x7F);
+        assert_eq!(cpu.get_flags(), Flags { zero: false, carry: false, overflow: true, negative: false });
     }
 
     #[test]
-    fn test_and_y_x() {
+    fn test_xor_and_not() {
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 0b0010;
+        cpu.registers[0] = 0b1100;
         cpu.registers[1] = 0b1010;
-        cpu.memory[0] = 0x50;
-        cpu.memory[1] = 0x12;
-        cpu.memory[2] = 0x00;
+        // xor r0, r1; not r2, r0
+        cpu.load_program(&[0xC0, 0x12, 0xC2, 0x03], 0).unwrap();
 
-        cpu.run();
+        cpu.run().unwrap();
 
-        assert_eq!(cpu.registers[2], 0b0010);
+        assert_eq!(cpu.registers[0], 0b0110);
+        assert_eq!(cpu.registers[2], 0b1111_1001);
+        assert_eq!(cpu.get_flags(), Flags { zero: false, carry: false, overflow: false, negative: true });
     }
 
     #[test]
-    fn test_and_x() {
+    fn test_shifts_and_rotates() {
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 0b1010;
-        cpu.memory[0] = 0x60;
-        cpu.memory[1] = 0xF2;
-        cpu.memory[2] = 0x00;
+        cpu.registers[0] = 0b1000_0001;
+        // shl r1, r0; shr r2, r0; rol r3, r0; ror r4, r0
+        cpu.load_program(&[0xC1, 0x04, 0xC2, 0x05, 0xC3, 0x06, 0xC4, 0x07], 0).unwrap();
 
-        cpu.run();
+        cpu.run_for(1).unwrap();
+        assert_eq!(cpu.registers[1], 0b0000_0010);
+        assert!(cpu.get_flags().carry);
 
-        assert_eq!(cpu.registers[2], 0b1010);
+        cpu.run().unwrap();
+        assert_eq!(cpu.registers[2], 0b0100_0000);
+        assert_eq!(cpu.registers[3], 0b0000_0011);
+        assert_eq!(cpu.registers[4], 0b1100_0000);
+        assert_eq!(cpu.get_flags(), Flags { zero: false, carry: true, overflow: false, negative: true });
     }
 
     #[test]
-    fn test_mov_y_x() {
+    fn test_mul() {
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 0b0010;
-        cpu.registers[1] = 0b1010;
-        cpu.memory[0] = 0x70;
-        cpu.memory[1] = 0x1F;
-        cpu.memory[2] = 0x00;
+        cpu.registers[0] = 200;
+        cpu.registers[1] = 3;
+        cpu.registers[2] = 12;
+        cpu.registers[3] = 10;
+        // mul r0, r1; mul r2, r3
+        cpu.load_program(&[0xC0, 0x18, 0xC2, 0x38], 0).unwrap();
 
-        cpu.run();
+        cpu.run_for(1).unwrap();
+        assert_eq!((cpu.registers[0], cpu.registers[1]), (0x58, 0x02)); // 600 = 0x0258
+        assert_eq!(cpu.get_flags(), Flags { zero: false, carry: true, overflow: false, negative: false });
 
-        assert_eq!(cpu.registers[0], 0b1010);
+        cpu.run().unwrap();
+        assert_eq!((cpu.registers[2], cpu.registers[3]), (120, 0));
+        assert_eq!(cpu.get_flags(), Flags { zero: false, carry: false, overflow: false, negative: false });
     }
 
     #[test]
-    fn test_mov_x() {
+    fn test_div_and_mod() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 0xF
+                    shl r0, r0      ; r0 = 30
+                    mov r1, r0
+                    movi r2, 7
+                    div r0, r2
+                    mod r1, r2
+                    halt
+            ",
+        )
+        .unwrap();
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 0b1010;
-        cpu.memory[0] = 0x80;
-        cpu.memory[1] = 0xF2;
-        cpu.memory[2] = 0x00;
+        cpu.load_program(&program.bytes, program.origin).unwrap();
 
-        cpu.run();
+        cpu.run().unwrap();
 
-        assert_eq!(cpu.registers[0], 0b1111);
+        assert_eq!(cpu.registers[0], 4);
+        assert_eq!(cpu.registers[1], 2);
     }
 
     #[test]
-    fn test_jump() {
+    fn test_divide_by_zero() {
         let mut cpu: Cpu = Cpu::new();
-        cpu.memory[0] = 0x90;
-        cpu.memory[1] = 0x26;
-        cpu.memory[0x26] = 0x30;
+        cpu.registers[0] = 9;
+        // nop; mod r0, r1
+        cpu.load_program(&[0x01, 0x11, 0xC0, 0x1A], 0).unwrap();
 
-        cpu.run();
+        assert_eq!(cpu.run(), Err(CpuError::DivideByZero { pc: 2 }));
+        assert_eq!(cpu.registers[0], 9);
+    }
 
-        assert_eq!(cpu.program_counter, 0x28);
+    #[test]
+    fn test_console_output() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r1, 0
+            loop:   ldi message
+                    ld r0, [i + r1]
+                    sei r0, 0
+                    jmp print
+                    halt
+            print:  ldi 0xF00
+                    st r0, [i]
+                    addi r1, 1 -> r1
+                    jmp loop
+            message: .byte 72, 105, 10, 0
+            ",
+        )
+        .unwrap();
+        let output: SharedBuffer = SharedBuffer::new();
+        let mut cpu: Cpu = Cpu::with_bus(MemoryMap::standard(Console::new(Box::new(io::empty()), Box::new(output.clone()))));
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        cpu.run().unwrap();
+
+        assert_eq!(output.contents(), b"Hi\n");
+        assert_eq!(cpu.bus.peek_u8(0xF00), 0);
     }
 
     #[test]
-    fn test_fn_call_and_ret() {
+    fn test_console_filter() {
+        // copy input to output with every byte incremented, counting bytes in r2
+        let program = crate::assembler::assemble(
+            "
+            loop:   ldi 0xF01
+                    ld r0, [i]          ; status
+                    sei r0, 0
+                    jmp copy
+                    halt
+            copy:   ldi 0xF02
+                    ld r0, [i]
+                    addi r0, 1 -> r0
+                    ldi 0xF00
+                    st r0, [i]
+                    addi r2, 1 -> r2
+                    jmp loop
+            ",
+        )
+        .unwrap();
+        let output: SharedBuffer = SharedBuffer::new();
+        let console: Console = Console::new(Box::new(io::Cursor::new(b"HAL".to_vec())), Box::new(output.clone()));
+        let mut cpu: Cpu = Cpu::with_bus(MemoryMap::standard(console));
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        cpu.run().unwrap();
+
+        assert_eq!(output.contents(), b"IBM");
+        assert_eq!(cpu.registers[2], 3);
+    }
+
+    #[test]
+    fn test_io_region_reads_zero() {
         let mut cpu: Cpu = Cpu::new();
-        cpu.registers[0] = 3;
-        cpu.registers[1] = 4;
+        cpu.registers[0] = 0xFF;
+        // ldi 0xF05; ld r0, [i]; st r0, [i]
+        cpu.load_program(&[0xEF, 0x05, 0xF0, 0x02, 0xF0, 0x03], 0).unwrap();
 
-        // Call function at address 0x100
-        cpu.memory[0] = 0x01;
-        cpu.memory[1] = 0x11;
-        cpu.memory[2] = 0xA1;
-        cpu.memory[3] = 0x00;
+        cpu.run().unwrap();
 
-        // terminate
-        cpu.memory[4] = 0x00;
-        cpu.memory[5] = 0x00;
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(cpu.bus.peek_u8(0xF05), 0);
+    }
 
-        // fn to add registers 0 and 1 loaded into hex address 0x100 in memory
-        cpu.memory[0x100] = 0x10;
-        cpu.memory[0x101] = 0x12;
-        cpu.memory[0x102] = 0xB0;
-        cpu.memory[0x103] = 0x00;
+    #[test]
+    fn test_custom_bus() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 9
+                    ldi 0x000
+                    st r0, [i]          ; ROM, ignored
+                    ldi 0x100
+                    st r0, [i]
+                    halt
+            ",
+        )
+        .unwrap();
+        let mut map: MemoryMap = MemoryMap::new();
+        map.map(0x000..=0x0FF, Box::new(Rom::new(vec![0; 0x100])));
+        map.map(0x100..=0x1FF, Box::new(Ram::new(0x100)));
+        let mut cpu: Cpu = Cpu::with_bus(map);
+        cpu.load_program(&program.bytes, program.origin).unwrap();
 
-        cpu.run();
+        cpu.run().unwrap();
 
-        assert_eq!(cpu.registers[2], 7);
-        assert_eq!(cpu.program_counter,  4);
+        assert_eq!(cpu.bus.peek_u8(0x000), program.bytes[0]);
+        assert_eq!(cpu.bus.peek_u8(0x100), 9);
+    }
+
+    #[test]
+    fn test_plain_ram_bus() {
+        let mut cpu: Cpu<Ram> = Cpu::with_bus(Ram::default());
+        cpu.registers[0] = 0x41;
+        // ldi 0xF00; st r0, [i]
+        cpu.load_program(&[0xEF, 0x00, 0xF0, 0x03], 0).unwrap();
+
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.get_bus().peek_u8(0xF00), 0x41);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let program = crate::assembler::assemble(
+            "
+                    ei
+            loop:   addi r0, 1 -> r0
+                    jmp loop
+            handler:
+                    addi r1, 1 -> r1
+                    reti
+                    .org 0xFF2          ; vector for line 1
+                    .word handler
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        let handler: u16 = program.labels["handler"];
+
+        cpu.run_for(3).unwrap();
+        assert_eq!(cpu.get_program_counter(), 2);
+        cpu.raise_interrupt(1);
+
+        assert_eq!(cpu.step(), Ok(Step::Interrupted { line: 1, handler }));
+        assert_eq!(cpu.get_program_counter(), handler);
+        assert!(!cpu.get_interrupts_enabled());
+        assert_eq!(cpu.stack_pointer, 1);
+
+        cpu.run_for(2).unwrap();
+        assert_eq!(cpu.registers[1], 1);
+        assert_eq!(cpu.get_program_counter(), 2);
+        assert!(cpu.get_interrupts_enabled());
+        assert_eq!(cpu.stack_pointer, 0);
+    }
+
+    #[test]
+    fn test_interrupts_masked_and_prioritised() {
+        let program = crate::assembler::assemble(
+            "
+                    nop
+                    ei
+                    nop
+                    halt
+            low:    movi r3, 3
+                    reti
+            high:   movi r2, 2
+                    reti
+                    .org 0xFF0
+                    .word high, low
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        cpu.raise_interrupt(1);
+        cpu.raise_interrupt(0);
+
+        // still disabled during the first nop
+        assert!(matches!(cpu.step(), Ok(Step::Executed { pc: 0, .. })));
+        assert!(matches!(cpu.step(), Ok(Step::Executed { pc: 2, .. })));
+        assert_eq!(cpu.step(), Ok(Step::Interrupted { line: 0, handler: program.labels["high"] }));
+        cpu.run_for(2).unwrap();
+        assert_eq!(cpu.step(), Ok(Step::Interrupted { line: 1, handler: program.labels["low"] }));
+
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+        assert_eq!(cpu.registers[2..4], [2, 3]);
+        assert_eq!(cpu.get_pending_interrupts(), 0);
+    }
+
+    #[test]
+    fn test_interrupt_wakes_halted_cpu() {
+        let program = crate::assembler::assemble(
+            "
+                    ei
+                    halt
+            handler:
+                    addi r0, 1 -> r0
+                    reti
+                    .org 0xFF0
+                    .word handler
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+        cpu.raise_interrupt(0);
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+
+        assert_eq!(cpu.registers[0], 1);
+        assert_eq!(cpu.get_program_counter(), 2);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 4
+                    ldi 0xF06           ; reload low byte
+                    st r0, [i]
+                    movi r0, 7          ; enable, interrupt, periodic
+                    ldi 0xF04
+                    st r0, [i]
+                    ei
+            loop:   jmp loop
+            handler:
+                    addi r1, 1 -> r1
+                    reti
+                    .org 0xFF0
+                    .word handler
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.set_cost_table(CostTable::uniform(1));
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+
+        // the counter starts ticking with the instruction that enables it
+        cpu.run_for(6).unwrap();
+        assert_eq!(cpu.get_bus().peek_u8(0xF08), 3);
+        cpu.run_for(3).unwrap();
+        assert_eq!(cpu.get_pending_interrupts(), 1);
+        assert_eq!(cpu.step(), Ok(Step::Interrupted { line: 0, handler: program.labels["handler"] }));
+
+        // every fourth cycle, with taking the interrupt costing one cycle like any step
+        cpu.run_for(40).unwrap();
+        assert_eq!(cpu.registers[1], 10);
+    }
+
+    #[test]
+    fn test_cycles() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 3          ; 1
+            loop:   sub r0, r1          ; 1
+                    sei r0, 0           ; 1
+                    jmp loop            ; 2
+                    call done           ; 3
+                    halt
+            done:   div r0, r2          ; faults without charging
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        cpu.registers[1] = 1;
+
+        assert_eq!(cpu.run_for_cycles(4), Ok(None));
+        assert_eq!(cpu.get_cycles(), 5);
+        assert_eq!(cpu.run_for_cycles(0), Ok(None));
+        assert_eq!(cpu.get_cycles(), 5);
+
+        assert!(cpu.run_for_cycles(100).is_err());
+        assert_eq!(cpu.get_cycles(), 1 + 3 * 2 + 2 * 2 + 3);
+
+        let mut costs: CostTable = CostTable::uniform(0);
+        costs.set("movi", 10).unwrap();
+        cpu.set_cost_table(costs);
+        cpu.set_program_counter(0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_cycles(), 24);
+
+        // a free step ends the run instead of spinning forever
+        assert_eq!(cpu.run_for_cycles(u64::MAX), Ok(None));
+        assert_eq!(cpu.get_program_counter(), 4);
+        assert_eq!(cpu.get_cycles(), 24);
+    }
+
+    #[test]
+    fn test_budget_stops_runaway_program() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.bus.poke_u8(0, 0x90); // jmp 0x000
+        cpu.set_budget(Some(Budget::Instructions(1000)));
+
+        assert_eq!(cpu.run(), Ok(HaltReason::BudgetExhausted));
+        assert_eq!(cpu.get_budget(), Some(Budget::Instructions(0)));
+        assert_eq!(cpu.get_cycles(), 2000);
+        assert_eq!(cpu.step(), Ok(Step::Halted(HaltReason::BudgetExhausted)));
+
+        cpu.set_budget(Some(Budget::Cycles(5)));
+        assert_eq!(cpu.run(), Ok(HaltReason::BudgetExhausted));
+        assert_eq!(cpu.get_budget(), Some(Budget::Cycles(1)));
+        assert_eq!(cpu.get_cycles(), 2004);
+    }
+
+    #[test]
+    fn test_gas_metering() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 6
+                    movi r1, 2
+                    movi r2, 2
+                    mul r0, r1          ; r1 gets the high byte
+                    div r0, r2
+                    halt
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        let mut gas_costs: CostTable = CostTable::uniform(1);
+        gas_costs.set("mul", 10).unwrap();
+        gas_costs.set("div", 20).unwrap();
+        cpu.set_gas_table(gas_costs);
+        cpu.set_budget(Some(Budget::Gas(25)));
+
+        // the div does not fit in what is left
+        assert_eq!(cpu.run(), Ok(HaltReason::BudgetExhausted));
+        assert_eq!(cpu.get_gas(), 13);
+        assert_eq!(cpu.get_program_counter(), 8);
+        assert_eq!(cpu.registers[0], 12);
+
+        cpu.set_budget(None);
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+        assert_eq!(cpu.get_gas(), 33);
+        assert_eq!(cpu.registers[0], 6);
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let program = crate::assembler::assemble(
+            "
+                    ldi 0xF00
+            loop:   st r0, [i]          ; print and count up
+                    addi r0, 1 -> r0
+                    call bump
+                    jmp loop
+            bump:   addi r1, 3 -> r1
+                    ret
+            ",
+        )
+        .unwrap();
+        let output: SharedBuffer = SharedBuffer::new();
+        let mut cpu: Cpu = Cpu::with_bus(MemoryMap::standard(Console::new(Box::new(io::empty()), Box::new(output.clone()))));
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        cpu.registers[0] = b'A';
+        cpu.set_budget(Some(Budget::Cycles(100)));
+        cpu.run_for(11).unwrap();
+        cpu.bus.poke_u8(0x800, 0xEE);
+        cpu.bus.poke_u8(0xF06, 9); // timer reload
+        let state: Vec<u8> = cpu.save_state();
+
+        let restored_output: SharedBuffer = SharedBuffer::new();
+        let mut restored: Cpu = Cpu::with_bus(MemoryMap::standard(Console::new(Box::new(io::empty()), Box::new(restored_output.clone()))));
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.registers, cpu.registers);
+        assert_eq!(restored.stack_pointer, 1);
+        assert_eq!(restored.get_flags(), cpu.get_flags());
+        assert_eq!(restored.get_cycles(), cpu.get_cycles());
+        assert_eq!(restored.get_bus().peek_u8(0x800), 0xEE);
+        assert_eq!(restored.get_bus().peek_u8(0xF06), 9);
+
+        // both continue identically
+        cpu.run_for(20).unwrap();
+        restored.run_for(20).unwrap();
+        assert_eq!(restored.save_state(), cpu.save_state());
+        assert_eq!(output.contents(), b"ABCDE");
+        assert_eq!(restored_output.contents(), b"CDE");
+    }
+
+    #[test]
+    fn test_load_state_errors() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.registers[3] = 3;
+        let state: Vec<u8> = cpu.save_state();
+        let mut other: Cpu = Cpu::new();
+
+        assert_eq!(other.load_state(b"nope"), Err(SnapshotError::BadMagic));
+        assert_eq!(other.load_state(&state[..state.len() - 1]), Err(SnapshotError::Truncated));
+        let mut version: Vec<u8> = state.clone();
+        version[5] = 2;
+        assert_eq!(other.load_state(&version), Err(SnapshotError::UnsupportedVersion(2)));
+        let mut trailing: Vec<u8> = state.clone();
+        trailing.push(0);
+        assert_eq!(other.load_state(&trailing), Err(SnapshotError::TrailingBytes));
+        // the index follows magic, version, registers, pc, stack, sp and flags
+        let mut index: Vec<u8> = state.clone();
+        index[58..60].copy_from_slice(&0xFFFF_u16.to_be_bytes());
+        assert_eq!(other.load_state(&index), Err(SnapshotError::Mismatch("index 0xffff".to_string())));
+
+        let mut ram_only: Cpu<Ram> = Cpu::with_bus(Ram::default());
+        assert!(ram_only.load_state(&state).is_err());
+        assert_eq!(other.registers[3], 0);
+
+        other.load_state(&state).unwrap();
+        assert_eq!(other.registers[3], 3);
+    }
+
+    #[test]
+    fn test_trace() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r0, 5
+                    call double
+                    halt
+            double: add r0, r0 -> r0
+                    ret
+            ",
+        )
+        .unwrap();
+        let output: SharedBuffer = SharedBuffer::new();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        cpu.set_tracer(Some(Box::new(TextTracer::new(output.clone()))));
+
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+        assert_eq!(
+            String::from_utf8(output.contents()).unwrap(),
+            "\
+0x000: 8050  movi r0, 0x5           sp=0  r0=0x05
+0x002: a006  call 0x006             sp=1
+0x006: 1000  add r0, r0 -> r0       sp=1  r0=0x0a
+0x008: b000  ret                    sp=0
+"
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let program = crate::assembler::assemble(
+            "
+            loop:   addi r0, 1 -> r0
+                    call count
+                    sei r0, 3
+                    jmp loop
+                    halt
+            count:  addi r1, 1 -> r1
+                    ret
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        let count: u16 = program.labels["count"];
+        assert!(cpu.add_breakpoint(count));
+        assert!(!cpu.add_breakpoint(count));
+
+        assert_eq!(cpu.run(), Ok(HaltReason::Breakpoint));
+        assert_eq!(cpu.get_program_counter(), count);
+        assert_eq!(cpu.get_stack(), &[2]);
+        assert_eq!(cpu.registers[1], 0);
+
+        // resuming executes the instruction under the breakpoint
+        assert_eq!(cpu.run_for(10), Ok(Some(HaltReason::Breakpoint)));
+        assert_eq!(cpu.registers[..2], [2, 1]);
+        assert!(matches!(cpu.step(), Ok(Step::Executed { pc, .. }) if pc == count));
+
+        assert!(cpu.remove_breakpoint(count));
+        assert!(!cpu.remove_breakpoint(count));
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+        assert_eq!(cpu.registers[..2], [3, 3]);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let program = crate::assembler::assemble(
+            "
+                    ldi 0x800
+                    movi r1, 5
+                    st r1, [i]
+                    ld r2, [i]
+                    add r2, r1 -> r3
+                    halt
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        cpu.add_watchpoint(WatchTarget::Memory(0x800), Access::Read);
+        cpu.add_watchpoint(WatchTarget::Register(3), Access::Write);
+        assert!(cpu.add_watchpoint(WatchTarget::Register(1), Access::Read));
+        assert!(cpu.remove_watchpoint(WatchTarget::Register(1), Access::Read));
+
+        // the store does not trigger the read watchpoint
+        assert_eq!(cpu.run(), Ok(HaltReason::Watchpoint { target: WatchTarget::Memory(0x800), access: Access::Read }));
+        assert_eq!(cpu.get_program_counter(), 8);
+        assert_eq!(cpu.registers[2], 5);
+        assert_eq!(cpu.run(), Ok(HaltReason::Watchpoint { target: WatchTarget::Register(3), access: Access::Write }));
+        assert_eq!(cpu.registers[3], 10);
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+
+        // single steps report hits without stopping
+        cpu.set_program_counter(6);
+        assert!(matches!(cpu.step(), Ok(Step::Executed { pc: 6, .. })));
+        assert_eq!(cpu.get_triggered(), Some(HaltReason::Watchpoint { target: WatchTarget::Memory(0x800), access: Access::Read }));
+        cpu.step().unwrap();
+        assert!(matches!(cpu.get_triggered(), Some(HaltReason::Watchpoint { .. })));
+    }
+
+    #[test]
+    fn test_conditions() {
+        let program = crate::assembler::assemble(
+            "
+            loop:   addi r2, 1 -> r2
+                    jmp loop
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        let already: usize = cpu.add_condition(Expression::parse("pc == 0").unwrap());
+        let seven: usize = cpu.add_condition(Expression::parse("r2 == 7 && pc > 0x0").unwrap());
+        let wrapped: usize = cpu.add_condition(Expression::parse("r2 == 0").unwrap());
+        assert_eq!(cpu.get_conditions().map(|(id, _)| id).collect::<Vec<usize>>(), vec![already, seven, wrapped]);
+
+        // `pc == 0` held when it was added, so only becoming true again stops
+        assert_eq!(cpu.run(), Ok(HaltReason::Condition(already)));
+        assert_eq!(cpu.registers[2], 1);
+        assert!(cpu.remove_condition(already));
+        assert_eq!(cpu.run(), Ok(HaltReason::Condition(seven)));
+        assert_eq!((cpu.registers[2], cpu.get_program_counter()), (7, 2));
+        assert_eq!(cpu.run(), Ok(HaltReason::Condition(wrapped)));
+        assert_eq!(cpu.registers[2], 0);
+        assert!(!cpu.remove_condition(already));
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let program = crate::assembler::assemble(
+            "
+                    movi r1, 5
+                    ldi 0x800
+                    st r1, [i]
+                    call bump
+                    st r1, [i]
+                    halt
+            bump:   addi r1, 1 -> r1
+                    ret
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        cpu.set_history_limit(100);
+        let mut states: Vec<Vec<u8>> = Vec::new();
+        loop {
+            states.push(cpu.save_state());
+            if let Step::Halted(_) = cpu.step().unwrap() {
+                break;
+            }
+        }
+        // the halt is not recorded
+        assert_eq!(cpu.get_history_len(), 7);
+
+        assert_eq!(cpu.reverse_continue(Some(WatchTarget::Memory(0x800))), Some(HaltReason::Watchpoint { target: WatchTarget::Memory(0x800), access: Access::Write }));
+        assert_eq!(cpu.get_program_counter(), 0x008);
+        assert_eq!(cpu.save_state(), states[6]);
+        assert_eq!(cpu.get_bus().peek_u8(0x800), 5);
+        assert_eq!(cpu.reverse_continue(Some(WatchTarget::Register(1))), Some(HaltReason::Watchpoint { target: WatchTarget::Register(1), access: Access::Write }));
+        assert_eq!((cpu.get_program_counter(), cpu.registers[1], cpu.get_stack()), (0x00c, 5, &[0x006][..]));
+        cpu.add_breakpoint(0x002);
+        assert_eq!(cpu.reverse_continue(None), Some(HaltReason::Breakpoint));
+        assert_eq!(cpu.save_state(), states[1]);
+        assert!(cpu.reverse_step());
+        assert_eq!(cpu.save_state(), states[0]);
+        assert!(!cpu.reverse_step());
+        assert_eq!(cpu.reverse_continue(None), None);
+
+        // replaying reaches the same end, and the limit keeps only the latest steps
+        assert_eq!(cpu.run(), Ok(HaltReason::Breakpoint));
+        assert_eq!(cpu.run(), Ok(HaltReason::Halt));
+        assert_eq!(cpu.save_state(), states[7]);
+        cpu.set_history_limit(2);
+        assert_eq!(cpu.get_history_len(), 2);
+        cpu.set_history_limit(0);
+        assert!(!cpu.reverse_step());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,649 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::assembler::{self, Program};
+use crate::bus::{Bus, MemoryMap, ADDRESS_SPACE};
+use crate::console::{Console, SharedBuffer};
+use crate::cpu::{Cpu, HaltReason, Step};
+use crate::json::Json;
+
+/*
+    Debug Adapter Protocol server, normally over stdin/stdout:
+    - messages are `Content-Length: <n>\r\n\r\n` followed by n bytes of JSON
+    - requests: initialize, launch, setBreakpoints, configurationDone, threads, stackTrace, scopes,
+      variables, continue, next, stepIn, stepOut, pause, readMemory, disconnect, terminate
+    - launch arguments: `program`, the path of an assembly source, and optional `stopOnEntry`
+    - one thread; frame 0 is at pc and the others at the call sites on the return stack
+    - a single Registers scope; `i` carries a memory reference for readMemory
+    - the guest's console output becomes `output` events and its console input is empty;
+      a halt instruction ends the debug session
+*/
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+/// Instructions run between checks for a pause request.
+const RUN_SLICE: u64 = 4096;
+/// The largest message body accepted from the client.
+const MAX_MESSAGE: usize = 4 << 20;
+
+/// Serves DAP requests read from `input` until the client disconnects or `input` ends.
+pub fn serve<R: BufRead + Send + 'static, W: Write>(input: R, output: W) -> io::Result<()> {
+    let (sender, requests) = mpsc::channel();
+    // a separate reader so a pause request can arrive while the program runs
+    thread::spawn(move || {
+        let mut input: R = input;
+        loop {
+            let message: io::Result<Option<Json>> = read_message(&mut input);
+            let done: bool = !matches!(message, Ok(Some(_)));
+            if let Some(message) = message.transpose() {
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+            if done {
+                return;
+            }
+        }
+    });
+    let mut server: DapServer<W> = DapServer { output, seq: 1, session: None, queued: VecDeque::new(), requests };
+    server.serve()
+}
+
+// what a launched program needs besides the CPU
+struct Session {
+    cpu: Cpu,
+    program: Program,
+    path: String,
+    console: SharedBuffer,
+    printed: usize, // console bytes already sent as output events
+    stop_on_entry: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    Next,    // until the stack is back to its depth, stepping over calls and interrupts
+    StepIn,  // one instruction
+    StepOut, // until the current call returns
+}
+
+// why a resumed program stopped
+enum Stop {
+    Stopped(&'static str),
+    Fault(String),
+    Exited,
+    Disconnected,
+    ReadError(io::Error),
+}
+
+struct DapServer<W: Write> {
+    output: W,
+    seq: i64,
+    session: Option<Session>,
+    queued: VecDeque<Json>, // requests that arrived while the program ran
+    requests: Receiver<io::Result<Json>>,
+}
+
+impl<W: Write> DapServer<W> {
+    fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let request: Json = match self.queued.pop_front() {
+                Some(request) => request,
+                None => match self.requests.recv() {
+                    Ok(request) => request?,
+                    Err(_) => return Ok(()),
+                },
+            };
+            if !self.handle(&request)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // returns `false` once the client disconnects
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let command: &str = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let arguments: &Json = request.get("arguments").unwrap_or(&Json::Null);
+        match command {
+            "initialize" => {
+                let capabilities: Json = Json::object(vec![("supportsConfigurationDoneRequest", Json::from(true)), ("supportsReadMemoryRequest", Json::from(true))]);
+                self.respond(request, Ok(capabilities))?;
+            }
+            "launch" => {
+                let result: Result<Json, String> = self.launch(arguments);
+                let launched: bool = result.is_ok();
+                self.respond(request, result)?;
+                if launched {
+                    self.event("initialized", Json::Null)?;
+                }
+            }
+            "configurationDone" => {
+                let stop_on_entry: Result<bool, String> = self.session().map(|session| session.stop_on_entry);
+                self.respond(request, stop_on_entry.clone().map(|_| Json::Null))?;
+                match stop_on_entry {
+                    Ok(true) => self.stopped("entry", None)?,
+                    Ok(false) => return self.resume(Resume::Continue),
+                    Err(_) => (),
+                }
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let result: Result<Json, String> = self.session().map(|_| Json::object(vec![("allThreadsContinued", Json::from(true))]));
+                let launched: bool = result.is_ok();
+                self.respond(request, result)?;
+                if launched {
+                    let mode: Resume = match command {
+                        "continue" => Resume::Continue,
+                        "next" => Resume::Next,
+                        "stepIn" => Resume::StepIn,
+                        _ => Resume::StepOut,
+                    };
+                    return self.resume(mode);
+                }
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null))?;
+                return Ok(false);
+            }
+            _ => {
+                let result: Result<Json, String> = match command {
+                    "setBreakpoints" => self.session().map(|session| session.set_breakpoints(arguments)),
+                    "threads" => Ok(Json::object(vec![("threads", Json::from(vec![Json::object(vec![("id", Json::from(THREAD_ID)), ("name", Json::from("cpu"))])]))])),
+                    "stackTrace" => self.session().map(|session| session.stack_trace()),
+                    "scopes" => Ok(Json::object(vec![(
+                        "scopes",
+                        Json::from(vec![Json::object(vec![
+                            ("name", Json::from("Registers")),
+                            ("presentationHint", Json::from("registers")),
+                            ("variablesReference", Json::from(REGISTERS_REFERENCE)),
+                            ("expensive", Json::from(false)),
+                        ])]),
+                    )])),
+                    "variables" => self.session().map(|session| session.variables(arguments)),
+                    "readMemory" => self.session().and_then(|session| session.read_memory(arguments)),
+                    // nothing is running between requests
+                    "pause" => Ok(Json::Null),
+                    _ => Err(format!("unsupported request `{}`", command)),
+                };
+                self.respond(request, result)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session.as_mut().ok_or_else(|| "no program has been launched".to_string())
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path: &str = arguments.get("program").and_then(Json::as_str).ok_or("launch needs a `program`")?;
+        let source: String = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let program: Program = assembler::assemble(&source).map_err(|e| format!("{}:{}", path, e))?;
+        let console: SharedBuffer = SharedBuffer::new();
+        let mut cpu: Cpu = Cpu::with_bus(MemoryMap::standard(Console::new(Box::new(io::empty()), Box::new(console.clone()))));
+        cpu.load_program(&program.bytes, program.origin).map_err(|e| format!("{}: {}", path, e))?;
+        cpu.set_program_counter(program.origin);
+        let stop_on_entry: bool = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+        self.session = Some(Session { cpu, program, path: path.to_string(), console, printed: 0, stop_on_entry });
+        Ok(Json::Null)
+    }
+
+    // runs the launched program and reports where it stopped; breakpoints at the starting pc
+    // are passed over. Returns `false` if the client went away while the program ran.
+    fn resume(&mut self, mode: Resume) -> io::Result<bool> {
+        let session: &mut Session = self.session.as_mut().expect("resume needs a session");
+        let cpu: &mut Cpu = &mut session.cpu;
+        let depth: usize = cpu.get_stack().len();
+        let mut pause: Option<Json> = None;
+        let mut count: u64 = 0;
+        let stop: Stop = loop {
+            if count > 0 && cpu.get_breakpoints().contains(&cpu.get_program_counter()) {
+                break Stop::Stopped("breakpoint");
+            }
+            match cpu.step() {
+                Ok(Step::Halted(HaltReason::Halt)) => break Stop::Exited,
+                Ok(Step::Halted(_)) => break Stop::Stopped("pause"),
+                Ok(_) => (),
+                Err(fault) => break Stop::Fault(fault.to_string()),
+            }
+            count += 1;
+            let done: bool = match mode {
+                Resume::Continue => false,
+                Resume::Next => cpu.get_stack().len() <= depth,
+                Resume::StepIn => true,
+                Resume::StepOut => cpu.get_stack().len() < depth,
+            };
+            if done {
+                break Stop::Stopped("step");
+            }
+            if count.is_multiple_of(RUN_SLICE) {
+                let stop: Option<Stop> = loop {
+                    match self.requests.try_recv() {
+                        Ok(Ok(request)) if request.get("command").and_then(Json::as_str) == Some("pause") => {
+                            pause = Some(request);
+                            break Some(Stop::Stopped("pause"));
+                        }
+                        Ok(Ok(request)) => self.queued.push_back(request),
+                        Ok(Err(error)) => break Some(Stop::ReadError(error)),
+                        Err(TryRecvError::Disconnected) => break Some(Stop::Disconnected),
+                        Err(TryRecvError::Empty) => break None,
+                    }
+                };
+                if let Some(stop) = stop {
+                    break stop;
+                }
+            }
+        };
+        if let Some(request) = pause {
+            self.respond(&request, Ok(Json::Null))?;
+        }
+        match stop {
+            Stop::Stopped(reason) => self.stopped(reason, None)?,
+            Stop::Fault(text) => self.stopped("exception", Some(text))?,
+            Stop::Exited => {
+                self.flush_console()?;
+                self.event("exited", Json::object(vec![("exitCode", Json::from(0))]))?;
+                self.event("terminated", Json::Null)?;
+            }
+            // requests queued before the client went away are dropped along with the session
+            Stop::Disconnected => return Ok(false),
+            Stop::ReadError(error) => return Err(error),
+        }
+        Ok(true)
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        self.flush_console()?;
+        let mut body: Vec<(&str, Json)> = vec![("reason", Json::from(reason)), ("threadId", Json::from(THREAD_ID)), ("allThreadsStopped", Json::from(true))];
+        if let Some(text) = text {
+            body.push(("description", Json::from("fault")));
+            body.push(("text", Json::from(text)));
+        }
+        self.event("stopped", Json::object(body))
+    }
+
+    // what the guest printed since the last output event
+    fn flush_console(&mut self) -> io::Result<()> {
+        let session: &mut Session = self.session.as_mut().expect("console output needs a session");
+        let contents: Vec<u8> = session.console.contents();
+        if contents.len() == session.printed {
+            return Ok(());
+        }
+        let text: String = String::from_utf8_lossy(&contents[session.printed..]).into_owned();
+        session.printed = contents.len();
+        self.event("output", Json::object(vec![("category", Json::from("stdout")), ("output", Json::from(text))]))
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let mut message: Vec<(&str, Json)> = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+        ];
+        match result {
+            Ok(Json::Null) => message.push(("success", Json::from(true))),
+            Ok(body) => {
+                message.push(("success", Json::from(true)));
+                message.push(("body", body));
+            }
+            Err(error) => {
+                message.push(("success", Json::from(false)));
+                message.push(("message", Json::from(error)));
+            }
+        }
+        self.send(message)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut message: Vec<(&str, Json)> = vec![("type", Json::from("event")), ("event", Json::from(event))];
+        if body != Json::Null {
+            message.push(("body", body));
+        }
+        self.send(message)
+    }
+
+    fn send(&mut self, mut message: Vec<(&str, Json)>) -> io::Result<()> {
+        message.insert(0, ("seq", Json::from(self.seq)));
+        self.seq += 1;
+        let text: String = Json::object(message).to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        self.output.flush()
+    }
+}
+
+impl Session {
+    // replaces every breakpoint; a line without an instruction gets the next instruction's
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        let path: Option<&str> = arguments.get("source").and_then(|source| source.get("path")).and_then(Json::as_str);
+        let ours: bool = path.is_some_and(|path| same_file(path, &self.path));
+        let requested: Vec<i64> = match arguments.get("breakpoints").and_then(Json::as_array) {
+            Some(breakpoints) => breakpoints.iter().filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_i64)).collect(),
+            None => Vec::new(),
+        };
+        if ours {
+            let old: Vec<u16> = self.cpu.get_breakpoints().iter().copied().collect();
+            for address in old {
+                self.cpu.remove_breakpoint(address);
+            }
+        }
+        let breakpoints: Vec<Json> = requested
+            .iter()
+            .map(|line| {
+                let found: Option<(&usize, &u16)> = if ours && *line > 0 { self.program.line_addresses.range(*line as usize..).next() } else { None };
+                match found {
+                    Some((line, address)) => {
+                        self.cpu.add_breakpoint(*address);
+                        Json::object(vec![("verified", Json::from(true)), ("line", Json::from(*line as i64))])
+                    }
+                    None if !ours => Json::object(vec![("verified", Json::from(false)), ("message", Json::from("not the launched program"))]),
+                    None => Json::object(vec![("verified", Json::from(false)), ("line", Json::from(*line)), ("message", Json::from("no instruction at or after this line"))]),
+                }
+            })
+            .collect();
+        Json::object(vec![("breakpoints", Json::from(breakpoints))])
+    }
+
+    fn stack_trace(&self) -> Json {
+        let mut addresses: Vec<u16> = vec![self.cpu.get_program_counter()];
+        addresses.extend(self.cpu.get_stack().iter().rev());
+        let name: &str = Path::new(&self.path).file_name().and_then(|name| name.to_str()).unwrap_or(&self.path);
+        let frames: Vec<Json> = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, address)| {
+                let mut frame: Vec<(&str, Json)> = vec![
+                    ("id", Json::from(id as i64)),
+                    ("name", Json::from(self.function(*address))),
+                    ("instructionPointerReference", Json::from(format!("0x{:03x}", address))),
+                ];
+                match self.program.line_addresses.iter().find(|(_, line_address)| **line_address == *address) {
+                    Some((line, _)) => {
+                        frame.push(("source", Json::object(vec![("name", Json::from(name)), ("path", Json::from(self.path.as_str()))])));
+                        frame.push(("line", Json::from(*line as i64)));
+                        frame.push(("column", Json::from(1)));
+                    }
+                    None => {
+                        frame.push(("line", Json::from(0)));
+                        frame.push(("column", Json::from(0)));
+                    }
+                }
+                Json::object(frame)
+            })
+            .collect();
+        let total: i64 = frames.len() as i64;
+        Json::object(vec![("stackFrames", Json::from(frames)), ("totalFrames", Json::from(total))])
+    }
+
+    // the closest label at or before `address`
+    fn function(&self, address: u16) -> String {
+        match self.program.labels.iter().filter(|(_, label_address)| **label_address <= address).max_by_key(|(_, label_address)| **label_address) {
+            Some((label, _)) => label.clone(),
+            None => format!("0x{:03x}", address),
+        }
+    }
+
+    fn variables(&self, arguments: &Json) -> Json {
+        if arguments.get("variablesReference").and_then(Json::as_i64) != Some(REGISTERS_REFERENCE) {
+            return Json::object(vec![("variables", Json::from(Vec::new()))]);
+        }
+        let variable = |name: String, value: String| Json::object(vec![("name", Json::from(name)), ("value", Json::from(value)), ("variablesReference", Json::from(0))]);
+        let mut variables: Vec<Json> = (0..16).map(|register| variable(format!("r{}", register), format!("0x{:02x}", self.cpu.get_value_at_register(register)))).collect();
+        variables.push(variable("pc".to_string(), format!("0x{:03x}", self.cpu.get_program_counter())));
+        let index: String = format!("0x{:03x}", self.cpu.get_index());
+        variables.push(Json::object(vec![
+            ("name", Json::from("i")),
+            ("value", Json::from(index.as_str())),
+            ("variablesReference", Json::from(0)),
+            ("memoryReference", Json::from(index.as_str())),
+        ]));
+        variables.push(variable("sp".to_string(), self.cpu.get_stack().len().to_string()));
+        variables.push(variable("flags".to_string(), self.cpu.get_flags().to_string()));
+        variables.push(variable("cycles".to_string(), self.cpu.get_cycles().to_string()));
+        Json::object(vec![("variables", Json::from(variables))])
+    }
+
+    // bytes past the end of memory are reported as unreadable
+    fn read_memory(&self, arguments: &Json) -> Result<Json, String> {
+        let reference: &str = arguments.get("memoryReference").and_then(Json::as_str).ok_or("readMemory needs a `memoryReference`")?;
+        let base: i64 = match reference.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => reference.parse::<i64>(),
+        }
+        .map_err(|_| format!("invalid memory reference `{}`", reference))?;
+        let invalid = || format!("invalid memory reference `{}`", reference);
+        let address: i64 = base.checked_add(arguments.get("offset").and_then(Json::as_i64).unwrap_or(0)).ok_or_else(invalid)?;
+        let count: i64 = arguments.get("count").and_then(Json::as_i64).ok_or("readMemory needs a `count`")?.max(0);
+        let start: i64 = address.clamp(0, ADDRESS_SPACE as i64);
+        let end: i64 = address.checked_add(count).ok_or_else(invalid)?.clamp(start, ADDRESS_SPACE as i64);
+        let bytes: Vec<u8> = self.cpu.get_bus().peek_range(start as u16, (end - start) as usize);
+        let mut body: Vec<(&str, Json)> = vec![("address", Json::from(format!("0x{:03x}", start))), ("data", Json::from(base64(&bytes)))];
+        if (bytes.len() as i64) < count {
+            body.push(("unreadableBytes", Json::from(count - bytes.len() as i64)));
+        }
+        Ok(Json::object(body))
+    }
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text: String = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group: u32 = chunk.iter().enumerate().fold(0, |group, (index, byte)| group | ((*byte as u32) << (16 - 8 * index)));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(ALPHABET[((group >> (18 - 6 * index)) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// Reads one `Content-Length` framed message. `None` at the end of `input`.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Json>> {
+    let mut length: Option<usize> = None;
+    loop {
+        let mut line: String = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line: &str = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length: usize = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "message without a Content-Length header"))?;
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message of {} bytes is too large", length)));
+    }
+    let mut body: Vec<u8> = vec![0; length];
+    input.read_exact(&mut body)?;
+    let text: String = String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Json::parse(&text).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "
+start:  movi r0, 2
+        call double
+        ldi 0x800
+        st r0, [i]
+        movi r1, 0x3
+        halt
+double: add r0, r0 -> r0
+        ret
+";
+
+    // runs a session over the requests `(command, arguments)` and returns every message sent
+    fn session(name: &str, source: &str, requests: &[(&str, &str)]) -> Vec<Json> {
+        let path: String = std::env::temp_dir().join(format!("cpu-emulator-dap-{}-{}.s", name, std::process::id())).to_string_lossy().into_owned();
+        fs::write(&path, source).unwrap();
+        let mut input: Vec<u8> = Vec::new();
+        for (seq, (command, arguments)) in requests.iter().enumerate() {
+            let arguments: String = arguments.replace("$PROGRAM", &path.replace('\\', "\\\\"));
+            let text: String = format!("{{\"seq\":{},\"type\":\"request\",\"command\":\"{}\",\"arguments\":{}}}", seq + 1, command, arguments);
+            input.extend_from_slice(format!("Content-Length: {}\r\n\r\n{}", text.len(), text).as_bytes());
+        }
+        let output: SharedBuffer = SharedBuffer::new();
+        serve(io::Cursor::new(input), output.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut output: io::Cursor<Vec<u8>> = io::Cursor::new(output.contents());
+        let mut messages: Vec<Json> = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    // the body of the response to request `seq`
+    fn response(messages: &[Json], seq: i64) -> &Json {
+        let response: &Json = messages.iter().find(|message| message.get("request_seq").and_then(Json::as_i64) == Some(seq)).unwrap();
+        assert_eq!(response.get("success"), Some(&Json::Bool(true)), "{}", response);
+        response.get("body").unwrap_or(&Json::Null)
+    }
+
+    fn events<'a>(messages: &'a [Json], event: &str) -> Vec<&'a Json> {
+        messages.iter().filter(|message| message.get("event").and_then(Json::as_str) == Some(event)).collect()
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let messages: Vec<Json> = session(
+            "stepping",
+            SOURCE,
+            &[
+                ("initialize", "{\"adapterID\":\"cpu\"}"),
+                ("launch", "{\"program\":\"$PROGRAM\"}"),
+                ("setBreakpoints", "{\"source\":{\"path\":\"$PROGRAM\"},\"breakpoints\":[{\"line\":8},{\"line\":10}]}"),
+                ("configurationDone", "{}"),
+                ("stackTrace", "{\"threadId\":1}"),
+                ("stepOut", "{\"threadId\":1}"),
+                ("next", "{\"threadId\":1}"),
+                ("variables", "{\"variablesReference\":1}"),
+                ("readMemory", "{\"memoryReference\":\"0x800\",\"count\":2}"),
+                ("readMemory", "{\"memoryReference\":\"0xffe\",\"offset\":1,\"count\":3}"),
+                ("readMemory", "{\"memoryReference\":\"0x7fffffffffffffff\",\"offset\":1,\"count\":1}"),
+                ("continue", "{\"threadId\":1}"),
+                ("disconnect", "{}"),
+            ],
+        );
+
+        // line 10 has no instruction
+        let breakpoints: String = response(&messages, 3).to_string();
+        assert_eq!(breakpoints, r#"{"breakpoints":[{"verified":true,"line":8},{"verified":false,"line":10,"message":"no instruction at or after this line"}]}"#);
+        let stopped: Vec<&Json> = events(&messages, "stopped");
+        assert_eq!(stopped.iter().map(|event| event.get("body").unwrap().get("reason").and_then(Json::as_str).unwrap()).collect::<Vec<&str>>(), vec!["breakpoint", "step", "step"]);
+
+        let frames: &[Json] = response(&messages, 5).get("stackFrames").and_then(Json::as_array).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("name").and_then(Json::as_str), Some("double"));
+        assert_eq!(frames[0].get("line").and_then(Json::as_i64), Some(8));
+        assert_eq!(frames[1].get("name").and_then(Json::as_str), Some("start"));
+        assert_eq!(frames[1].get("line").and_then(Json::as_i64), Some(3));
+        assert_eq!(frames[1].get("instructionPointerReference").and_then(Json::as_str), Some("0x002"));
+
+        // stepOut returns past the call and `next` runs the ldi
+        let variables: &[Json] = response(&messages, 8).get("variables").and_then(Json::as_array).unwrap();
+        let value = |name: &str| variables.iter().find(|variable| variable.get("name").and_then(Json::as_str) == Some(name)).unwrap().get("value").and_then(Json::as_str).unwrap();
+        assert_eq!((value("r0"), value("pc"), value("i")), ("0x04", "0x006", "0x800"));
+
+        assert_eq!(response(&messages, 9).to_string(), r#"{"address":"0x800","data":"AAA="}"#);
+        assert_eq!(response(&messages, 10).to_string(), r#"{"address":"0xfff","data":"AA==","unreadableBytes":2}"#);
+        let overflow: &Json = messages.iter().find(|message| message.get("request_seq").and_then(Json::as_i64) == Some(11)).unwrap();
+        assert_eq!(overflow.get("message").and_then(Json::as_str), Some("invalid memory reference `0x7fffffffffffffff`"));
+        assert_eq!(events(&messages, "exited").len(), 1);
+        assert_eq!(events(&messages, "terminated").len(), 1);
+    }
+
+    #[test]
+    fn test_output_and_pause() {
+        let messages: Vec<Json> = session(
+            "pause",
+            "
+            loop:   ldi 0xf00
+                    movi r0, 0xa
+                    st r0, [i]
+                    jmp loop
+            ",
+            &[
+                ("initialize", "{}"),
+                ("launch", "{\"program\":\"$PROGRAM\",\"stopOnEntry\":true}"),
+                ("configurationDone", "{}"),
+                ("stepIn", "{\"threadId\":1}"),
+                ("continue", "{\"threadId\":1}"),
+                ("pause", "{\"threadId\":1}"),
+                ("threads", "{}"),
+                ("frobnicate", "{}"),
+            ],
+        );
+
+        let stopped: Vec<&Json> = events(&messages, "stopped");
+        assert_eq!(stopped.iter().map(|event| event.get("body").unwrap().get("reason").and_then(Json::as_str).unwrap()).collect::<Vec<&str>>(), vec!["entry", "step", "pause"]);
+        let output: &Json = events(&messages, "output")[0].get("body").unwrap();
+        assert!(output.get("output").and_then(Json::as_str).unwrap().starts_with("\n\n"));
+        response(&messages, 6);
+        assert_eq!(response(&messages, 7).to_string(), r#"{"threads":[{"id":1,"name":"cpu"}]}"#);
+        let unsupported: &Json = messages.iter().find(|message| message.get("request_seq").and_then(Json::as_i64) == Some(8)).unwrap();
+        assert_eq!(unsupported.get("message").and_then(Json::as_str), Some("unsupported request `frobnicate`"));
+    }
+
+    #[test]
+    fn test_client_gone_while_running() {
+        let path: String = std::env::temp_dir().join(format!("cpu-emulator-dap-gone-{}.s", std::process::id())).to_string_lossy().into_owned();
+        fs::write(&path, "loop: jmp loop\n").unwrap();
+        let launch: String = format!("{{\"seq\":1,\"command\":\"launch\",\"arguments\":{{\"program\":\"{}\"}}}}", path.replace('\\', "\\\\"));
+        let configured: &str = "{\"seq\":2,\"command\":\"configurationDone\"}";
+        let server = |requests: Receiver<io::Result<Json>>| DapServer { output: io::sink(), seq: 1, session: None, queued: VecDeque::new(), requests };
+
+        // the reader thread ends, dropping its sender, while the guest loops forever
+        let (sender, requests) = mpsc::channel();
+        sender.send(Ok(Json::parse(&launch).unwrap())).unwrap();
+        sender.send(Ok(Json::parse(configured).unwrap())).unwrap();
+        drop(sender);
+        assert!(server(requests).serve().is_ok());
+
+        let (sender, requests) = mpsc::channel();
+        sender.send(Ok(Json::parse(&launch).unwrap())).unwrap();
+        sender.send(Ok(Json::parse(configured).unwrap())).unwrap();
+        sender.send(Err(io::Error::new(io::ErrorKind::InvalidData, "message without a Content-Length header"))).unwrap();
+        assert_eq!(server(requests).serve().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(sender);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_message() {
+        let mut input: io::Cursor<&[u8]> = io::Cursor::new(b"Content-Length: 2\r\n\r\n{}Content-Length: 1000000000\r\n\r\n{}");
+        assert_eq!(read_message(&mut input).unwrap(), Some(Json::object(vec![])));
+        assert_eq!(read_message(&mut input).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut input: io::Cursor<&[u8]> = io::Cursor::new(b"\r\n{}");
+        assert_eq!(read_message(&mut input).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+}
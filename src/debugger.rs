@@ -0,0 +1,508 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+use crate::bus::{Bus, MemoryMap, ADDRESS_SPACE};
+use crate::cpu::{Access, Cpu, HaltReason, Step, WatchTarget};
+use crate::disassembler;
+use crate::expression::Expression;
+
+/*
+    debugger commands, locations are numbers (0x hex or decimal) or labels:
+    - s, step [n]: execute n instructions (default 1), ignoring breakpoints
+    - c, continue: run until the program halts, faults or reaches a breakpoint
+    - rs, reverse-step [n]: undo n instructions (default 1)
+    - rc, reverse-continue [rN | location]: undo instructions back to the last write of a register
+      or memory byte, or back to a breakpoint
+    - b, break [location]: set a breakpoint, or list breakpoints without a location
+    - d, delete <location>: clear a breakpoint
+    - watch, rwatch, awatch [rN | location]: stop after a write, read or any access of a register
+      or memory byte, or list watchpoints without a target
+    - unwatch <rN | location>: clear every watchpoint on a target
+    - cond [expression]: stop when the expression becomes true (see expression.rs), or list
+      conditions without one
+    - uncond <id>: clear a condition
+    - r, regs: registers, flags, index, stack pointer and cycle count
+    - m, mem <location> [len]: hex dump of len bytes (default 64)
+    - stack: return addresses on the stack, most recent first
+    - l, list [location] [count]: disassemble count instructions (default 8) from location or pc
+    - set <rN | pc | i> <value or location>: edit a register
+    - write <location> <byte>...: edit memory, ignoring write protection
+    - h, help
+    - q, quit
+*/
+
+const HELP: &str = "\
+s, step [n]                   execute n instructions
+c, continue                   run until halt, fault or breakpoint
+rs, reverse-step [n]          undo n instructions
+rc, reverse-continue [target] undo back to the last write of rN or location, or a breakpoint
+b, break [location]           set a breakpoint, or list breakpoints
+d, delete <location>          clear a breakpoint
+watch [rN | location]         stop after a write, or list watchpoints
+rwatch <rN | location>        stop after a read
+awatch <rN | location>        stop after any access
+unwatch <rN | location>       clear watchpoints
+cond [expression]             stop when the expression becomes true, or list conditions
+uncond <id>                   clear a condition
+r, regs                       show registers
+m, mem <location> [len]       dump memory
+stack                         show the return stack
+l, list [location] [count]    disassemble
+set <rN | pc | i> <value>     edit a register
+write <location> <byte>...    edit memory
+q, quit                       leave the debugger";
+
+/// Instructions the debugger can reverse unless the CPU already records a history.
+pub const DEFAULT_HISTORY: usize = 100_000;
+
+/// Command-line debugger around a [`Cpu`]. `labels` come from the assembler and can be used
+/// anywhere an address is expected.
+pub struct Debugger<B: Bus = MemoryMap> {
+    cpu: Cpu<B>,
+    labels: BTreeMap<String, u16>,
+}
+
+impl<B: Bus> Debugger<B> {
+    /// Turns on history recording with [`DEFAULT_HISTORY`] if `cpu` is not recording.
+    pub fn new(mut cpu: Cpu<B>, labels: BTreeMap<String, u16>) -> Debugger<B> {
+        if cpu.get_history_limit() == 0 {
+            cpu.set_history_limit(DEFAULT_HISTORY);
+        }
+        Debugger { cpu, labels }
+    }
+
+    pub fn get_cpu(&self) -> &Cpu<B> {
+        &self.cpu
+    }
+
+    pub fn get_cpu_mut(&mut self) -> &mut Cpu<B> {
+        &mut self.cpu
+    }
+
+    /// Prompts for and executes commands until `quit` or the end of `input`.
+    pub fn repl(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+        writeln!(output, "{}", self.position())?;
+        loop {
+            write!(output, "(cpu) ")?;
+            output.flush()?;
+            let mut line: String = String::new();
+            if input.read_line(&mut line)? == 0 || !self.execute(&line, output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes one command line and writes its output. Returns `false` once the user quits.
+    pub fn execute(&mut self, line: &str, output: &mut dyn Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let Some(&("q" | "quit")) = words.first() {
+            return Ok(false);
+        }
+        match self.command(&words) {
+            Ok(text) if text.is_empty() => (),
+            Ok(text) => writeln!(output, "{}", text)?,
+            Err(message) => writeln!(output, "error: {}", message)?,
+        }
+        Ok(true)
+    }
+
+    fn command(&mut self, words: &[&str]) -> Result<String, String> {
+        match words {
+            [] => Ok(String::new()),
+            ["s" | "step"] => Ok(self.step(1)),
+            ["s" | "step", count] => Ok(self.step(parse_number(count, u32::MAX)?)),
+            ["c" | "continue"] => Ok(self.resume()),
+            ["rs" | "reverse-step"] => Ok(self.reverse_step(1)),
+            ["rs" | "reverse-step", count] => Ok(self.reverse_step(parse_number(count, u32::MAX)?)),
+            ["rc" | "reverse-continue"] => Ok(self.reverse_continue(None)),
+            ["rc" | "reverse-continue", target] => {
+                let target: WatchTarget = self.target(target)?;
+                Ok(self.reverse_continue(Some(target)))
+            }
+            ["b" | "break"] => Ok(self.breakpoints()),
+            ["b" | "break", location] => {
+                let address: u16 = self.location(location)?;
+                self.cpu.add_breakpoint(address);
+                Ok(format!("breakpoint at {}", self.describe(address)))
+            }
+            ["d" | "delete", location] => {
+                let address: u16 = self.location(location)?;
+                if !self.cpu.remove_breakpoint(address) {
+                    return Err(format!("no breakpoint at {}", self.describe(address)));
+                }
+                Ok(format!("deleted breakpoint at {}", self.describe(address)))
+            }
+            ["watch"] => Ok(self.watchpoints()),
+            ["watch", target] => self.watch(target, &[Access::Write]),
+            ["rwatch", target] => self.watch(target, &[Access::Read]),
+            ["awatch", target] => self.watch(target, &[Access::Read, Access::Write]),
+            ["unwatch", target] => {
+                let target: WatchTarget = self.target(target)?;
+                let removed: usize = [Access::Read, Access::Write].iter().filter(|access| self.cpu.remove_watchpoint(target, **access)).count();
+                if removed == 0 {
+                    return Err(format!("no watchpoint on {}", target));
+                }
+                Ok(format!("deleted watchpoint on {}", target))
+            }
+            ["cond"] => Ok(self.conditions()),
+            ["cond", ..] => {
+                let condition: Expression = Expression::parse(&words[1..].join(" ")).map_err(|e| format!("bad condition, {}", e))?;
+                let text: String = condition.to_string();
+                let id: usize = self.cpu.add_condition(condition);
+                Ok(format!("condition {}: {}", id, text))
+            }
+            ["uncond", id] => {
+                let id: u32 = parse_number(id, u32::MAX)?;
+                if !self.cpu.remove_condition(id as usize) {
+                    return Err(format!("no condition {}", id));
+                }
+                Ok(format!("deleted condition {}", id))
+            }
+            ["r" | "regs"] => Ok(self.registers()),
+            ["m" | "mem", location] => self.memory(location, "64"),
+            ["m" | "mem", location, len] => self.memory(location, len),
+            ["stack"] => Ok(self.stack()),
+            ["l" | "list"] => Ok(self.list(self.cpu.get_program_counter(), 8)),
+            ["l" | "list", location] => Ok(self.list(self.location(location)?, 8)),
+            ["l" | "list", location, count] => Ok(self.list(self.location(location)?, parse_number(count, 0xFFF)? as u16)),
+            ["set", register, value] => self.set(register, value),
+            ["write", location, bytes @ ..] if !bytes.is_empty() => {
+                let address: u16 = self.location(location)?;
+                let values: Vec<u8> = bytes.iter().map(|byte| parse_number(byte, 0xFF).map(|value| value as u8)).collect::<Result<_, _>>()?;
+                if address as usize + values.len() > ADDRESS_SPACE {
+                    return Err("write runs past the end of memory".to_string());
+                }
+                for (offset, value) in values.iter().enumerate() {
+                    self.cpu.get_bus_mut().poke_u8(address + offset as u16, *value);
+                }
+                Ok(String::new())
+            }
+            ["h" | "help"] => Ok(HELP.to_string()),
+            [command, ..] => Err(format!("unknown command or arguments for `{}`, try `help`", command)),
+        }
+    }
+
+    fn step(&mut self, count: u32) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        for _ in 0..count {
+            match self.cpu.step() {
+                Ok(Step::Executed { .. }) => (),
+                Ok(Step::Interrupted { line, handler }) => lines.push(format!("interrupt {} -> {}", line, self.describe(handler))),
+                Ok(Step::Halted(reason)) => {
+                    lines.push(self.stopped(reason));
+                    break;
+                }
+                Err(fault) => {
+                    lines.push(format!("fault: {}", fault));
+                    break;
+                }
+            }
+            if let Some(reason) = self.cpu.get_triggered() {
+                lines.push(self.stopped(reason));
+                break;
+            }
+        }
+        lines.push(self.position());
+        lines.join("\n")
+    }
+
+    fn resume(&mut self) -> String {
+        let stop: String = match self.cpu.run() {
+            Ok(reason) => self.stopped(reason),
+            Err(fault) => format!("fault: {}", fault),
+        };
+        format!("{}\n{}", stop, self.position())
+    }
+
+    fn reverse_step(&mut self, count: u32) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        for _ in 0..count {
+            if !self.cpu.reverse_step() {
+                lines.push("no earlier history".to_string());
+                break;
+            }
+        }
+        lines.push(self.position());
+        lines.join("\n")
+    }
+
+    fn reverse_continue(&mut self, target: Option<WatchTarget>) -> String {
+        let reason: Option<HaltReason> = self.cpu.reverse_continue(target);
+        let pc: String = self.describe(self.cpu.get_program_counter());
+        let stop: String = match reason {
+            Some(HaltReason::Watchpoint { target, .. }) => format!("{} last written at {}", target, pc),
+            Some(HaltReason::Breakpoint) => format!("breakpoint at {}", pc),
+            _ => format!("reached the start of history at {}", pc),
+        };
+        format!("{}\n{}", stop, self.position())
+    }
+
+    fn stopped(&self, reason: HaltReason) -> String {
+        let pc: String = self.describe(self.cpu.get_program_counter());
+        match reason {
+            HaltReason::Halt => format!("halted at {}", pc),
+            HaltReason::BudgetExhausted => format!("budget exhausted at {}", pc),
+            HaltReason::Breakpoint => format!("breakpoint at {}", pc),
+            HaltReason::Watchpoint { target, access: Access::Read } => format!("{} read, stopped at {}", target, pc),
+            HaltReason::Watchpoint { target, access: Access::Write } => format!("{} written, stopped at {}", target, pc),
+            HaltReason::Condition(id) => {
+                let condition: String = self.cpu.get_conditions().find(|(other, _)| *other == id).map(|(_, condition)| condition.to_string()).unwrap_or_default();
+                format!("condition {} became true: {}, stopped at {}", id, condition, pc)
+            }
+        }
+    }
+
+    // the instruction about to execute
+    fn position(&self) -> String {
+        self.list(self.cpu.get_program_counter(), 1)
+    }
+
+    fn breakpoints(&self) -> String {
+        if self.cpu.get_breakpoints().is_empty() {
+            return "no breakpoints".to_string();
+        }
+        let lines: Vec<String> = self.cpu.get_breakpoints().iter().map(|address| self.describe(*address)).collect();
+        lines.join("\n")
+    }
+
+    fn watch(&mut self, target: &str, accesses: &[Access]) -> Result<String, String> {
+        let target: WatchTarget = self.target(target)?;
+        for access in accesses {
+            self.cpu.add_watchpoint(target, *access);
+        }
+        Ok(format!("watchpoint on {}", target))
+    }
+
+    fn watchpoints(&self) -> String {
+        if self.cpu.get_watchpoints().is_empty() {
+            return "no watchpoints".to_string();
+        }
+        let lines: Vec<String> = self
+            .cpu
+            .get_watchpoints()
+            .iter()
+            .map(|(target, access)| format!("{} {}", target, if *access == Access::Read { "read" } else { "write" }))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn conditions(&self) -> String {
+        let lines: Vec<String> = self.cpu.get_conditions().map(|(id, condition)| format!("{}: {}", id, condition)).collect();
+        if lines.is_empty() {
+            return "no conditions".to_string();
+        }
+        lines.join("\n")
+    }
+
+    fn registers(&self) -> String {
+        let mut lines: Vec<String> = (0..4)
+            .map(|row| {
+                let line: Vec<String> = (0..4)
+                    .map(|col| row * 4 + col)
+                    .map(|register| format!("r{:<2} = 0x{:02x}", register, self.cpu.get_value_at_register(register)))
+                    .collect();
+                line.join("  ")
+            })
+            .collect();
+        lines.push(format!(
+            "pc  = 0x{:03x}  i = 0x{:03x}  sp = {}  flags = {}  cycles = {}",
+            self.cpu.get_program_counter(),
+            self.cpu.get_index(),
+            self.cpu.get_stack().len(),
+            self.cpu.get_flags(),
+            self.cpu.get_cycles()
+        ));
+        lines.join("\n")
+    }
+
+    fn memory(&self, location: &str, len: &str) -> Result<String, String> {
+        let start: u16 = self.location(location)?;
+        let len: usize = (parse_number(len, 0x1000)? as usize).min(ADDRESS_SPACE - start as usize);
+        let bytes: Vec<u8> = self.cpu.get_bus().peek_range(start, len);
+        let lines: Vec<String> = bytes
+            .chunks(16)
+            .enumerate()
+            .map(|(row, chunk)| {
+                let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+                format!("0x{:03x}: {}", start as usize + row * 16, hex.join(" "))
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    fn stack(&self) -> String {
+        let stack: &[u16] = self.cpu.get_stack();
+        if stack.is_empty() {
+            return "stack is empty".to_string();
+        }
+        let lines: Vec<String> = stack.iter().rev().enumerate().map(|(depth, address)| format!("#{} {}", depth, self.describe(*address))).collect();
+        lines.join("\n")
+    }
+
+    // `*` marks a breakpoint and `>` the program counter
+    fn list(&self, start: u16, count: u16) -> String {
+        let len: usize = (count as usize * 2).min(ADDRESS_SPACE - start as usize);
+        let bytes: Vec<u8> = self.cpu.get_bus().peek_range(start, len);
+        let mut lines: Vec<String> = Vec::new();
+        for line in disassembler::disassemble(&bytes, start) {
+            let address: u16 = line.address();
+            for (label, _) in self.labels.iter().filter(|(_, label_address)| **label_address == address) {
+                lines.push(format!("{}:", label));
+            }
+            let breakpoint: char = if self.cpu.get_breakpoints().contains(&address) { '*' } else { ' ' };
+            let current: char = if address == self.cpu.get_program_counter() { '>' } else { ' ' };
+            lines.push(format!("{}{} {}", breakpoint, current, line));
+        }
+        lines.join("\n")
+    }
+
+    fn set(&mut self, register: &str, value: &str) -> Result<String, String> {
+        match register {
+            "pc" => self.cpu.set_program_counter(self.location(value)?),
+            "i" => self.cpu.set_index(self.location(value)?),
+            _ => {
+                let register: u8 = register
+                    .strip_prefix('r')
+                    .and_then(|number| number.parse::<u8>().ok())
+                    .filter(|number| *number < 16)
+                    .ok_or_else(|| format!("unknown register `{}`", register))?;
+                self.cpu.set_value_at_register(register, parse_number(value, 0xFF)? as u8);
+            }
+        }
+        Ok(String::new())
+    }
+
+    fn target(&self, text: &str) -> Result<WatchTarget, String> {
+        match text.strip_prefix('r').and_then(|number| number.parse::<u8>().ok()) {
+            Some(register) if register < 16 => Ok(WatchTarget::Register(register)),
+            Some(_) => Err(format!("unknown register `{}`", text)),
+            None => self.location(text).map(WatchTarget::Memory),
+        }
+    }
+
+    fn location(&self, text: &str) -> Result<u16, String> {
+        if let Some(address) = self.labels.get(text) {
+            return Ok(*address);
+        }
+        parse_number(text, ADDRESS_SPACE as u32 - 1).map(|address| address as u16).map_err(|_| format!("unknown location `{}`", text))
+    }
+
+    // an address, with its label if it has one
+    fn describe(&self, address: u16) -> String {
+        match self.labels.iter().find(|(_, label_address)| **label_address == address) {
+            Some((label, _)) => format!("0x{:03x} <{}>", address, label),
+            None => format!("0x{:03x}", address),
+        }
+    }
+}
+
+/// Parses a `0x`-prefixed hex or plain decimal number no larger than `max`.
+fn parse_number(text: &str, max: u32) -> Result<u32, String> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>(),
+    };
+    match parsed {
+        Ok(value) if value <= max => Ok(value),
+        _ => Err(format!("invalid number `{}`", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debugger() -> Debugger {
+        let program = crate::assembler::assemble(
+            "
+            start:  movi r0, 2
+                    call double
+                    halt
+            double: add r0, r0 -> r0
+                    ret
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        Debugger::new(cpu, program.labels)
+    }
+
+    fn run(debugger: &mut Debugger, line: &str) -> String {
+        let mut output: Vec<u8> = Vec::new();
+        assert!(debugger.execute(line, &mut output).unwrap());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_step_and_breakpoints() {
+        let mut debugger: Debugger = debugger();
+
+        assert_eq!(run(&mut debugger, "break double"), "breakpoint at 0x006 <double>\n");
+        assert_eq!(run(&mut debugger, "b"), "0x006 <double>\n");
+        assert_eq!(run(&mut debugger, "continue"), "breakpoint at 0x006 <double>\ndouble:\n*> 0x006: 1000  add r0, r0 -> r0\n");
+        assert_eq!(run(&mut debugger, "stack"), "#0 0x002\n");
+        assert_eq!(run(&mut debugger, "step 2"), " > 0x004: 0000  halt\n");
+        assert_eq!(run(&mut debugger, "delete 6"), "deleted breakpoint at 0x006 <double>\n");
+        assert_eq!(run(&mut debugger, "d double"), "error: no breakpoint at 0x006 <double>\n");
+        assert_eq!(run(&mut debugger, "c"), "halted at 0x004\n > 0x004: 0000  halt\n");
+        assert_eq!(debugger.get_cpu().get_value_at_register(0), 4);
+    }
+
+    #[test]
+    fn test_inspect_and_edit() {
+        let mut debugger: Debugger = debugger();
+
+        run(&mut debugger, "set r3 0x7f");
+        run(&mut debugger, "set pc double");
+        run(&mut debugger, "set i 0x123");
+        run(&mut debugger, "write 0x800 1 2 0xff");
+        assert_eq!(run(&mut debugger, "mem 0x800 3"), "0x800: 01 02 ff\n");
+        assert_eq!(run(&mut debugger, "m 0x7f0 20").lines().count(), 2);
+        let registers: String = run(&mut debugger, "regs");
+        assert!(registers.contains("r3  = 0x7f"));
+        assert!(registers.contains("pc  = 0x006  i = 0x123  sp = 0"));
+        assert_eq!(run(&mut debugger, "list start 2"), "start:\n   0x000: 8020  movi r0, 0x2\n   0x002: a006  call 0x006\n");
+
+        assert_eq!(run(&mut debugger, "set r16 1"), "error: unknown register `r16`\n");
+        assert_eq!(run(&mut debugger, "write nowhere 1"), "error: unknown location `nowhere`\n");
+        assert_eq!(run(&mut debugger, "write 0xfff 1 2"), "error: write runs past the end of memory\n");
+        assert!(run(&mut debugger, "frobnicate").starts_with("error: unknown command"));
+        assert!(!debugger.execute("quit", &mut Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut debugger: Debugger = debugger();
+
+        run(&mut debugger, "c");
+        assert_eq!(run(&mut debugger, "rc r0"), "r0 last written at 0x006 <double>\ndouble:\n > 0x006: 1000  add r0, r0 -> r0\n");
+        assert_eq!(debugger.get_cpu().get_value_at_register(0), 2);
+        assert_eq!(run(&mut debugger, "rs"), " > 0x002: a006  call 0x006\n");
+        assert_eq!(run(&mut debugger, "reverse-step 5"), "no earlier history\nstart:\n > 0x000: 8020  movi r0, 0x2\n");
+        run(&mut debugger, "b double");
+        run(&mut debugger, "c");
+        run(&mut debugger, "c");
+        assert_eq!(run(&mut debugger, "reverse-continue"), "breakpoint at 0x006 <double>\ndouble:\n*> 0x006: 1000  add r0, r0 -> r0\n");
+        assert_eq!(run(&mut debugger, "rc 0x800"), "reached the start of history at 0x000 <start>\nstart:\n > 0x000: 8020  movi r0, 0x2\n");
+    }
+
+    #[test]
+    fn test_watchpoints_and_conditions() {
+        let mut debugger: Debugger = debugger();
+
+        assert_eq!(run(&mut debugger, "watch r0"), "watchpoint on r0\n");
+        assert_eq!(run(&mut debugger, "rwatch 0x800"), "watchpoint on 0x800\n");
+        assert_eq!(run(&mut debugger, "watch"), "r0 write\n0x800 read\n");
+        assert_eq!(run(&mut debugger, "c"), "r0 written, stopped at 0x002\n > 0x002: a006  call 0x006\n");
+        assert_eq!(run(&mut debugger, "unwatch r0"), "deleted watchpoint on r0\n");
+        assert_eq!(run(&mut debugger, "unwatch r0"), "error: no watchpoint on r0\n");
+
+        assert_eq!(run(&mut debugger, "cond r0 == 4 && pc >= 0x6"), "condition 1: r0 == 4 && pc >= 0x6\n");
+        assert_eq!(run(&mut debugger, "cond"), "1: r0 == 4 && pc >= 0x6\n");
+        assert_eq!(run(&mut debugger, "step 5"), "condition 1 became true: r0 == 4 && pc >= 0x6, stopped at 0x008\n > 0x008: b000  ret\n");
+        assert_eq!(run(&mut debugger, "uncond 1"), "deleted condition 1\n");
+        assert_eq!(run(&mut debugger, "cond r0 =="), "error: bad condition, column 6: expected a value, found end of expression\n");
+        assert_eq!(run(&mut debugger, "c"), "halted at 0x004\n > 0x004: 0000  halt\n");
+    }
+}
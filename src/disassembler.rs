@@ -0,0 +1,102 @@
+use std::fmt;
+
+use crate::instruction::Instruction;
+
+/// One disassembled memory location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Instruction { address: u16, opcode: u16, instruction: Instruction },
+    /// A word that does not decode to an operation, or a trailing odd byte.
+    Data { address: u16, bytes: Vec<u8> },
+}
+
+impl Line {
+    pub fn address(&self) -> u16 {
+        match self {
+            Line::Instruction { address, .. } | Line::Data { address, .. } => *address,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Instruction { address, opcode, instruction } => {
+                write!(f, "0x{:03x}: {:04x}  {}", address, opcode, instruction)
+            }
+            Line::Data { address, bytes } => match bytes.as_slice() {
+                [high, low] => write!(f, "0x{:03x}: {:02x}{:02x}  .word 0x{:02x}{:02x}", address, high, low, high, low),
+                _ => {
+                    let hex: Vec<String> = bytes.iter().map(|byte| format!("0x{:02x}", byte)).collect();
+                    let raw: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    write!(f, "0x{:03x}: {:<4}  .byte {}", address, raw, hex.join(", "))
+                }
+            },
+        }
+    }
+}
+
+/// Disassembles `bytes`, which are located in memory starting at `origin`, one word at a time.
+/// Decoding goes through [`Instruction::decode`], exactly as `Cpu::step` does.
+pub fn disassemble(bytes: &[u8], origin: u16) -> Vec<Line> {
+    bytes
+        .chunks(2)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let address: u16 = origin.wrapping_add((index as u16).wrapping_mul(2));
+            match *chunk {
+                [high, low] => {
+                    let opcode: u16 = u16::from_be_bytes([high, low]);
+                    match Instruction::decode(opcode) {
+                        Instruction::Unknown(_) => Line::Data { address, bytes: chunk.to_vec() },
+                        instruction => Line::Instruction { address, opcode, instruction },
+                    }
+                }
+                _ => Line::Data { address, bytes: chunk.to_vec() },
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let lines: Vec<Line> = disassemble(&[0x10, 0x12, 0x0F, 0xFF, 0xA1, 0x00, 0x7F], 0x100);
+
+        assert_eq!(
+            lines,
+            vec![
+                Line::Instruction { address: 0x100, opcode: 0x1012, instruction: Instruction::Add { x: 0, y: 1, z: 2 } },
+                Line::Data { address: 0x102, bytes: vec![0x0F, 0xFF] },
+                Line::Instruction { address: 0x104, opcode: 0xA100, instruction: Instruction::Call { address: 0x100 } },
+                Line::Data { address: 0x106, bytes: vec![0x7F] },
+            ]
+        );
+        assert_eq!(lines[0].to_string(), "0x100: 1012  add r0, r1 -> r2");
+        assert_eq!(lines[1].to_string(), "0x102: 0fff  .word 0x0fff");
+        assert_eq!(lines[2].to_string(), "0x104: a100  call 0x100");
+        assert_eq!(lines[3].to_string(), "0x106: 7f    .byte 0x7f");
+
+        // addresses wrap around past the end of the 16-bit address space
+        let lines: Vec<Line> = disassemble(&vec![0; 0x10002], 0x100);
+        assert_eq!(lines.last(), Some(&Line::Instruction { address: 0x100, opcode: 0x0000, instruction: Instruction::Halt }));
+    }
+
+    #[test]
+    fn test_disassemble_assembled_program() {
+        let source: &str = "
+            .org 0x20
+            start: movi r0, 3
+                   call start
+                   .word 0x0FFF
+                   halt
+        ";
+        let program = crate::assembler::assemble(source).unwrap();
+        let text: Vec<String> = disassemble(&program.bytes, program.origin).iter().map(Line::to_string).collect();
+
+        assert_eq!(text, vec!["0x020: 8030  movi r0, 0x3", "0x022: a020  call 0x020", "0x024: 0fff  .word 0x0fff", "0x026: 0000  halt"]);
+    }
+}
@@ -0,0 +1,337 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::instruction::Flag;
+
+/*
+    condition expressions, e.g. `r2 == 7 && pc > 0x100`:
+    - operands: numbers (decimal, 0x hex, 0b binary), r0 - r15, pc, i, sp (stack depth),
+      cycles, the flags z c v n (0 or 1), and [expression] for the memory byte at an address
+    - operators, loosest binding first: ||, &&, comparisons (== != < <= > >=), + -, unary ! and -
+    - parentheses group; any nonzero value is true and comparisons yield 1 or 0
+*/
+
+/// Why an expression did not parse. `column` is 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpressionError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl Error for ExpressionError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Register(u8),
+    ProgramCounter,
+    Index,
+    StackPointer,
+    Cycles,
+    Flag(Flag),
+    Memory(Box<Node>),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+}
+
+/// A parsed condition over the machine state, evaluated with [`Expression::evaluate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, ExpressionError> {
+        let mut parser: Parser = Parser { tokens: tokenize(source)?, position: 0, end: source.len() + 1 };
+        let root: Node = parser.or()?;
+        if let Some((column, token)) = parser.tokens.get(parser.position) {
+            return Err(ExpressionError { column: *column, message: format!("unexpected `{}`", token) });
+        }
+        Ok(Expression { source: source.trim().to_string(), root })
+    }
+
+    pub fn evaluate<B: Bus>(&self, cpu: &Cpu<B>) -> i64 {
+        evaluate(&self.root, cpu)
+    }
+
+    /// Whether the expression evaluates to a nonzero value.
+    pub fn is_true<B: Bus>(&self, cpu: &Cpu<B>) -> bool {
+        self.evaluate(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn evaluate<B: Bus>(node: &Node, cpu: &Cpu<B>) -> i64 {
+    match node {
+        Node::Number(value) => *value,
+        Node::Register(register) => cpu.get_value_at_register(*register) as i64,
+        Node::ProgramCounter => cpu.get_program_counter() as i64,
+        Node::Index => cpu.get_index() as i64,
+        Node::StackPointer => cpu.get_stack().len() as i64,
+        Node::Cycles => cpu.get_cycles() as i64,
+        Node::Flag(flag) => cpu.get_flags().get(*flag) as i64,
+        Node::Memory(address) => cpu.get_bus().peek_u8(evaluate(address, cpu) as u16 & 0xFFF) as i64,
+        Node::Not(operand) => (evaluate(operand, cpu) == 0) as i64,
+        Node::Negate(operand) => evaluate(operand, cpu).wrapping_neg(),
+        Node::Binary(operator, left, right) => {
+            let left: i64 = evaluate(left, cpu);
+            // && and || short-circuit
+            match operator {
+                Operator::Or => return (left != 0 || evaluate(right, cpu) != 0) as i64,
+                Operator::And => return (left != 0 && evaluate(right, cpu) != 0) as i64,
+                _ => (),
+            }
+            let right: i64 = evaluate(right, cpu);
+            match operator {
+                Operator::Equal => (left == right) as i64,
+                Operator::NotEqual => (left != right) as i64,
+                Operator::Less => (left < right) as i64,
+                Operator::LessEqual => (left <= right) as i64,
+                Operator::Greater => (left > right) as i64,
+                Operator::GreaterEqual => (left >= right) as i64,
+                Operator::Add => left.wrapping_add(right),
+                Operator::Subtract => left.wrapping_sub(right),
+                Operator::Or | Operator::And => unreachable!(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Number(i64),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// longest symbols first, so `<=` is not read as `<` followed by `=`
+const SYMBOLS: [&str; 15] = ["||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "!", "(", ")", "[", "]"];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+    let mut tokens: Vec<(usize, Token)> = Vec::new();
+    let mut rest: &str = source;
+    while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+        rest = &rest[start..];
+        let column: usize = source.len() - rest.len() + 1;
+        let symbol: Option<&'static str> = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)).copied();
+        let len: usize = match symbol {
+            Some(symbol) => {
+                tokens.push((column, Token::Symbol(symbol)));
+                symbol.len()
+            }
+            None => {
+                let len: usize = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                if len == 0 {
+                    return Err(ExpressionError { column, message: format!("unexpected character `{}`", rest.chars().next().unwrap()) });
+                }
+                let word: &str = &rest[..len];
+                let token: Token = if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    let parsed = if let Some(hex) = word.strip_prefix("0x") {
+                        i64::from_str_radix(hex, 16)
+                    } else if let Some(binary) = word.strip_prefix("0b") {
+                        i64::from_str_radix(binary, 2)
+                    } else {
+                        word.parse::<i64>()
+                    };
+                    Token::Number(parsed.map_err(|_| ExpressionError { column, message: format!("invalid number `{}`", word) })?)
+                } else {
+                    Token::Name(word.to_lowercase())
+                };
+                tokens.push((column, token));
+                len
+            }
+        };
+        rest = &rest[len..];
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize, // column just past the source, for errors at the end
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.end, |(column, _)| *column)
+    }
+
+    fn error(&self, message: &str) -> ExpressionError {
+        match self.peek() {
+            Some(token) => ExpressionError { column: self.column(), message: format!("{}, found `{}`", message, token) },
+            None => ExpressionError { column: self.column(), message: format!("{}, found end of expression", message) },
+        }
+    }
+
+    // consumes the next token if it is one of `symbols`
+    fn symbol(&mut self, symbols: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Symbol(symbol)) if symbols.contains(symbol) => {
+                let symbol: &'static str = symbol;
+                self.position += 1;
+                Some(symbol)
+            }
+            _ => None,
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, ExpressionError> {
+        let mut node: Node = self.and()?;
+        while self.symbol(&["||"]).is_some() {
+            node = Node::Binary(Operator::Or, Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, ExpressionError> {
+        let mut node: Node = self.comparison()?;
+        while self.symbol(&["&&"]).is_some() {
+            node = Node::Binary(Operator::And, Box::new(node), Box::new(self.comparison()?));
+        }
+        Ok(node)
+    }
+
+    fn comparison(&mut self) -> Result<Node, ExpressionError> {
+        let node: Node = self.sum()?;
+        let operator: Operator = match self.symbol(&["==", "!=", "<", "<=", ">", ">="]) {
+            Some("==") => Operator::Equal,
+            Some("!=") => Operator::NotEqual,
+            Some("<") => Operator::Less,
+            Some("<=") => Operator::LessEqual,
+            Some(">") => Operator::Greater,
+            Some(">=") => Operator::GreaterEqual,
+            _ => return Ok(node),
+        };
+        Ok(Node::Binary(operator, Box::new(node), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Node, ExpressionError> {
+        let mut node: Node = self.unary()?;
+        while let Some(symbol) = self.symbol(&["+", "-"]) {
+            let operator: Operator = if symbol == "+" { Operator::Add } else { Operator::Subtract };
+            node = Node::Binary(operator, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        match self.symbol(&["!", "-"]) {
+            Some("!") => Ok(Node::Not(Box::new(self.unary()?))),
+            Some(_) => Ok(Node::Negate(Box::new(self.unary()?))),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, ExpressionError> {
+        if self.symbol(&["("]).is_some() {
+            let node: Node = self.or()?;
+            return match self.symbol(&[")"]) {
+                Some(_) => Ok(node),
+                None => Err(self.error("expected `)`")),
+            };
+        }
+        if self.symbol(&["["]).is_some() {
+            let node: Node = self.or()?;
+            return match self.symbol(&["]"]) {
+                Some(_) => Ok(Node::Memory(Box::new(node))),
+                None => Err(self.error("expected `]`")),
+            };
+        }
+        let node: Node = match self.peek() {
+            Some(Token::Number(value)) => Node::Number(*value),
+            Some(Token::Name(name)) => match name.as_str() {
+                "pc" => Node::ProgramCounter,
+                "i" => Node::Index,
+                "sp" => Node::StackPointer,
+                "cycles" => Node::Cycles,
+                _ => match (Flag::from_name(name), name.strip_prefix('r').and_then(|number| number.parse::<u8>().ok())) {
+                    (Some(flag), _) => Node::Flag(flag),
+                    (None, Some(register)) if register < 16 => Node::Register(register),
+                    _ => return Err(ExpressionError { column: self.column(), message: format!("unknown name `{}`", name) }),
+                },
+            },
+            _ => return Err(self.error("expected a value")),
+        };
+        self.position += 1;
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let mut cpu: Cpu = Cpu::new();
+        cpu.set_value_at_register(2, 7);
+        cpu.set_program_counter(0x104);
+        cpu.set_index(0x800);
+        cpu.get_bus_mut().poke_u8(0x801, 0x42);
+
+        let value = |source: &str| Expression::parse(source).unwrap().evaluate(&cpu);
+        assert_eq!(value("r2 == 7 && pc > 0x100"), 1);
+        assert_eq!(value("r2 == 7 && pc > 0x200 || !r3"), 1);
+        assert_eq!(value("R2 + 1 - 0b10"), 6);
+        assert_eq!(value("[i + 1] == 0x42"), 1);
+        assert_eq!(value("-(r2) <= -7 && z == 0 && sp == 0"), 1);
+        assert_eq!(value("r2 != 7 || r2 >= 8"), 0);
+        assert_eq!(Expression::parse("  r2 ==  7 ").unwrap().to_string(), "r2 ==  7");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |source: &str| Expression::parse(source).unwrap_err().to_string();
+        assert_eq!(error("r2 =="), "column 6: expected a value, found end of expression");
+        assert_eq!(error("r16 == 1"), "column 1: unknown name `r16`");
+        assert_eq!(error("(r1 == 1"), "column 9: expected `)`, found end of expression");
+        assert_eq!(error("r1 == 1 2"), "column 9: unexpected `2`");
+        assert_eq!(error("r1 = 1"), "column 4: unexpected character `=`");
+        assert_eq!(error("[pc"), "column 4: expected `]`, found end of expression");
+        assert_eq!(error("0xzz"), "column 1: invalid number `0xzz`");
+    }
+}
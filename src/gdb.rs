@@ -0,0 +1,464 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::bus::{Bus, MemoryMap, ADDRESS_SPACE};
+use crate::cpu::{Cpu, CpuError, HaltReason, Step};
+
+/*
+    GDB remote serial protocol stub, one debugger connection at a time:
+    - registers: r0 - r15 (8 bits each) then pc (16 bits), in that order in `g`/`G` packets,
+      big-endian like the machine's opcodes; `target.xml` describes them to GDB
+    - packets: ? g G p P m M s c Z0 z0 qSupported qXfer:features:read qAttached H D k,
+      anything else gets the empty "unsupported" reply
+    - memory accesses peek and poke, so they have no device side effects and ignore write protection
+    - stop replies carry a signal: SIGTRAP after a step, a breakpoint or a halt instruction,
+      SIGINT after ^C, and SIGILL, SIGFPE or SIGSEGV after a fault
+*/
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// Instructions a `c` packet runs between checks for a ^C from the debugger.
+const CONTINUE_SLICE: usize = 4096;
+
+/// GDB register number of the program counter; r0 - r15 are 0 - 15.
+const PC_REGNUM: usize = 16;
+
+/// Serves GDB's remote serial protocol for a [`Cpu`], e.g. to `target remote localhost:1234`.
+pub struct GdbStub<B: Bus = MemoryMap> {
+    cpu: Cpu<B>,
+    stop: String, // reply to `?`, the last stop reply sent
+}
+
+impl<B: Bus> GdbStub<B> {
+    pub fn new(cpu: Cpu<B>) -> GdbStub<B> {
+        GdbStub { cpu, stop: signal(SIGTRAP) }
+    }
+
+    pub fn get_cpu(&self) -> &Cpu<B> {
+        &self.cpu
+    }
+
+    pub fn get_cpu_mut(&mut self) -> &mut Cpu<B> {
+        &mut self.cpu
+    }
+
+    /// Answers packets on `stream` until the debugger detaches, kills the target or disconnects.
+    pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        while let Some(packet) = read_packet(&mut stream)? {
+            let mut interrupted = || poll_interrupt(&stream);
+            let reply: Option<String> = self.command(&packet, &mut interrupted);
+            match reply {
+                Some(reply) => write_packet(&mut stream, &reply)?,
+                None => return Ok(()),
+            }
+            if packet == "D" {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers one packet, without its framing. `interrupted` is polled while the program runs
+    /// and stops it once it returns `true`. Returns `None` when the session ends without a reply.
+    pub fn command(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        let (kind, body): (char, &str) = match packet.chars().next() {
+            Some(kind) => (kind, &packet[kind.len_utf8()..]),
+            None => return Some(String::new()),
+        };
+        let reply: String = match kind {
+            '?' => self.stop.clone(),
+            'g' => {
+                let mut reply: String = (0..16).map(|register| format!("{:02x}", self.cpu.get_value_at_register(register))).collect();
+                reply.push_str(&format!("{:04x}", self.cpu.get_program_counter()));
+                reply
+            }
+            'G' => match decode_hex(body) {
+                Some(bytes) if bytes.len() == 18 => {
+                    for (register, value) in bytes[..16].iter().enumerate() {
+                        self.cpu.set_value_at_register(register as u8, *value);
+                    }
+                    self.cpu.set_program_counter(u16::from_be_bytes([bytes[16], bytes[17]]));
+                    "OK".to_string()
+                }
+                _ => error(1),
+            },
+            'p' => match usize::from_str_radix(body, 16) {
+                Ok(register) if register < 16 => format!("{:02x}", self.cpu.get_value_at_register(register as u8)),
+                Ok(PC_REGNUM) => format!("{:04x}", self.cpu.get_program_counter()),
+                _ => error(1),
+            },
+            'P' => self.write_register(body).unwrap_or_else(|| error(1)),
+            'm' => self.read_memory(body).unwrap_or_else(|| error(1)),
+            'M' => self.write_memory(body).unwrap_or_else(|| error(1)),
+            's' | 'c' => {
+                if !body.is_empty() {
+                    match parse_hex(body) {
+                        Some(address) => self.cpu.set_program_counter(address),
+                        None => return Some(error(1)),
+                    }
+                }
+                self.stop = if kind == 's' { self.step() } else { self.resume(interrupted) };
+                self.stop.clone()
+            }
+            'Z' | 'z' => match body.strip_prefix("0,").and_then(|rest| rest.split(',').next()).and_then(parse_hex) {
+                Some(address) => {
+                    if kind == 'Z' {
+                        self.cpu.add_breakpoint(address);
+                    } else {
+                        self.cpu.remove_breakpoint(address);
+                    }
+                    "OK".to_string()
+                }
+                None => String::new(),
+            },
+            'q' => self.query(body),
+            'H' | 'D' => "OK".to_string(),
+            'k' => return None,
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn step(&mut self) -> String {
+        match self.cpu.step() {
+            Ok(Step::Halted(reason)) => stop_reply(Ok(reason)),
+            Ok(_) => signal(SIGTRAP),
+            Err(fault) => stop_reply(Err(fault)),
+        }
+    }
+
+    // runs in slices so a ^C can stop a program that never halts
+    fn resume(&mut self, interrupted: &mut dyn FnMut() -> bool) -> String {
+        let mut first: bool = true;
+        loop {
+            // `run_for` ignores a breakpoint at the address it starts from
+            if !first && self.cpu.get_breakpoints().contains(&self.cpu.get_program_counter()) {
+                return stop_reply(Ok(HaltReason::Breakpoint));
+            }
+            match self.cpu.run_for(CONTINUE_SLICE) {
+                Ok(Some(reason)) => return stop_reply(Ok(reason)),
+                Ok(None) => (),
+                Err(fault) => return stop_reply(Err(fault)),
+            }
+            if interrupted() {
+                return signal(SIGINT);
+            }
+            first = false;
+        }
+    }
+
+    // P<register>=<value>
+    fn write_register(&mut self, body: &str) -> Option<String> {
+        let (register, value): (&str, &str) = body.split_once('=')?;
+        let bytes: Vec<u8> = decode_hex(value)?;
+        match (usize::from_str_radix(register, 16).ok()?, bytes.as_slice()) {
+            (register, [value]) if register < 16 => self.cpu.set_value_at_register(register as u8, *value),
+            (PC_REGNUM, [high, low]) => self.cpu.set_program_counter(u16::from_be_bytes([*high, *low])),
+            _ => return None,
+        }
+        Some("OK".to_string())
+    }
+
+    // m<address>,<length>; a read running past the end of memory is cut short
+    fn read_memory(&self, body: &str) -> Option<String> {
+        let (address, len): (u16, usize) = parse_range(body)?;
+        let len: usize = len.min(ADDRESS_SPACE - address as usize);
+        let bytes: Vec<u8> = self.cpu.get_bus().peek_range(address, len);
+        Some(encode_hex(&bytes))
+    }
+
+    // M<address>,<length>:<bytes>
+    fn write_memory(&mut self, body: &str) -> Option<String> {
+        let (range, data): (&str, &str) = body.split_once(':')?;
+        let (address, len): (u16, usize) = parse_range(range)?;
+        let bytes: Vec<u8> = decode_hex(data)?;
+        if bytes.len() != len || address as usize + len > ADDRESS_SPACE {
+            return None;
+        }
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.cpu.get_bus_mut().poke_u8(address + offset as u16, *byte);
+        }
+        Some("OK".to_string())
+    }
+
+    fn query(&self, body: &str) -> String {
+        if body.starts_with("Supported") {
+            return "PacketSize=1000;qXfer:features:read+".to_string();
+        }
+        if body == "Attached" {
+            return "1".to_string();
+        }
+        // Xfer:features:read:target.xml:<offset>,<length>
+        if let Some(request) = body.strip_prefix("Xfer:features:read:") {
+            return match request.strip_prefix("target.xml:").and_then(|range| range.split_once(',')) {
+                Some((offset, len)) => match (usize::from_str_radix(offset, 16), usize::from_str_radix(len, 16)) {
+                    (Ok(offset), Ok(len)) => {
+                        let description: String = target_description();
+                        let start: usize = offset.min(description.len());
+                        let end: usize = start.saturating_add(len).min(description.len());
+                        let more: char = if end < description.len() { 'm' } else { 'l' };
+                        format!("{}{}", more, &description[start..end])
+                    }
+                    _ => error(1),
+                },
+                None => error(0),
+            };
+        }
+        String::new()
+    }
+}
+
+/// The GDB target description for this machine: r0 - r15 and the program counter.
+pub fn target_description() -> String {
+    let mut registers: String = String::new();
+    for register in 0..16 {
+        registers.push_str(&format!("    <reg name=\"r{}\" bitsize=\"8\" type=\"uint8\" regnum=\"{}\"/>\n", register, register));
+    }
+    registers.push_str(&format!("    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" regnum=\"{}\"/>\n", PC_REGNUM));
+    format!(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.cpu-emulator.core\">\n{}  </feature>\n</target>\n",
+        registers
+    )
+}
+
+fn stop_reply(result: Result<HaltReason, CpuError>) -> String {
+    match result {
+        Ok(_) => signal(SIGTRAP),
+        Err(CpuError::InvalidOpcode { .. } | CpuError::PcOutOfBounds { .. }) => signal(SIGILL),
+        Err(CpuError::DivideByZero { .. }) => signal(SIGFPE),
+        Err(CpuError::StackOverflow { .. } | CpuError::StackUnderflow { .. } | CpuError::MemoryOutOfBounds { .. }) => signal(SIGSEGV),
+        Err(_) => signal(SIGTRAP),
+    }
+}
+
+fn signal(number: u8) -> String {
+    format!("S{:02x}", number)
+}
+
+fn error(number: u8) -> String {
+    format!("E{:02x}", number)
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+// <address>,<length> with the address inside memory
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (address, len): (&str, &str) = text.split_once(',')?;
+    let address: u16 = parse_hex(address).filter(|address| (*address as usize) < ADDRESS_SPACE)?;
+    Some((address, usize::from_str_radix(len, 16).ok()?))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok()).collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, byte| sum.wrapping_add(*byte))
+}
+
+/// Reads the next `$<data>#<checksum>` packet and acknowledges it, asking for a resend while
+/// the checksum is wrong. Acks and ^Cs between packets are skipped. `None` at end of stream.
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<String>> {
+    loop {
+        let mut byte: [u8; 1] = [0];
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data: Vec<u8> = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum: [u8; 2] = [0; 2];
+        if stream.read_exact(&mut sum).is_err() {
+            return Ok(None);
+        }
+        let expected: Option<u8> = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+/// Sends `data` as a packet, escaping the framing characters, and resends it until the
+/// debugger acknowledges it.
+fn write_packet<S: Read + Write>(stream: &mut S, data: &str) -> io::Result<()> {
+    let mut escaped: Vec<u8> = Vec::with_capacity(data.len());
+    for byte in data.bytes() {
+        match byte {
+            b'$' | b'#' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', byte ^ 0x20]),
+            _ => escaped.push(byte),
+        }
+    }
+    let mut packet: Vec<u8> = vec![b'$'];
+    packet.extend_from_slice(&escaped);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&escaped)).as_bytes());
+    loop {
+        stream.write_all(&packet)?;
+        let mut ack: [u8; 1] = [0];
+        loop {
+            match stream.read(&mut ack)? {
+                0 => return Ok(()),
+                _ if ack[0] == b'+' => return Ok(()),
+                _ if ack[0] == b'-' => break,
+                _ => (),
+            }
+        }
+    }
+}
+
+// whether the debugger sent a ^C, or went away, since the program was resumed
+fn poll_interrupt(mut stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte: [u8; 1] = [0];
+    let interrupted: bool = match stream.read(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => byte[0] == 0x03,
+        Err(_) => false,
+    };
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn stub() -> GdbStub {
+        let program = crate::assembler::assemble(
+            "
+            start:  movi r0, 2
+                    call double
+                    halt
+            double: add r0, r0 -> r0
+                    ret
+            ",
+        )
+        .unwrap();
+        let mut cpu: Cpu = Cpu::new();
+        cpu.load_program(&program.bytes, program.origin).unwrap();
+        GdbStub::new(cpu)
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        stub.command(packet, &mut || false).unwrap()
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub: GdbStub = stub();
+
+        assert_eq!(reply(&mut stub, "P3=7f"), "OK");
+        assert_eq!(reply(&mut stub, "P10=0006"), "OK");
+        assert_eq!(reply(&mut stub, "g"), "0000007f0000000000000000000000000006");
+        assert_eq!(reply(&mut stub, "p3"), "7f");
+        assert_eq!(reply(&mut stub, "p10"), "0006");
+        assert_eq!(reply(&mut stub, "G0102030405060708090a0b0c0d0e0f100008"), "OK");
+        assert_eq!(stub.get_cpu().get_value_at_register(15), 0x10);
+        assert_eq!(stub.get_cpu().get_program_counter(), 8);
+        assert_eq!(reply(&mut stub, "p11"), "E01");
+
+        assert_eq!(reply(&mut stub, "m0,4"), "8020a006");
+        assert_eq!(reply(&mut stub, "M800,3:0102ff"), "OK");
+        assert_eq!(reply(&mut stub, "m800,3"), "0102ff");
+        assert_eq!(reply(&mut stub, "mffe,8"), "0000");
+        assert_eq!(reply(&mut stub, "m1000,1"), "E01");
+        assert_eq!(reply(&mut stub, "Mfff,2:0102"), "E01");
+    }
+
+    #[test]
+    fn test_execution() {
+        let mut stub: GdbStub = stub();
+
+        assert_eq!(reply(&mut stub, "Z0,6,2"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "S05");
+        assert_eq!(stub.get_cpu().get_program_counter(), 6);
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "p0"), "04");
+        assert_eq!(reply(&mut stub, "z0,6,2"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "S05");
+        assert_eq!(stub.get_cpu().get_program_counter(), 4);
+        assert_eq!(reply(&mut stub, "?"), "S05");
+
+        // a fault, and a loop stopped by ^C
+        assert_eq!(reply(&mut stub, "M0,2:f00f"), "OK");
+        assert_eq!(reply(&mut stub, "c0"), "S04");
+        assert_eq!(reply(&mut stub, "M0,2:9000"), "OK");
+        assert_eq!(stub.command("c0", &mut || true), Some("S02".to_string()));
+        assert_eq!(reply(&mut stub, "vCont?"), "");
+        // a non-ASCII first byte arrives as U+FFFD and is just another unsupported packet
+        assert_eq!(reply(&mut stub, &String::from_utf8_lossy(b"\xffg")), "");
+        assert_eq!(stub.command("k", &mut || false), None);
+    }
+
+    #[test]
+    fn test_target_description() {
+        let mut stub: GdbStub = stub();
+
+        assert!(reply(&mut stub, "qSupported:multiprocess+;xmlRegisters=i386").contains("qXfer:features:read+"));
+        let description: String = target_description();
+        let first: String = reply(&mut stub, "qXfer:features:read:target.xml:0,20");
+        assert_eq!(first, format!("m{}", &description[..0x20]));
+        let rest: String = reply(&mut stub, &format!("qXfer:features:read:target.xml:20,{:x}", description.len()));
+        assert_eq!(rest, format!("l{}", &description[0x20..]));
+        assert_eq!(reply(&mut stub, "qXfer:features:read:target.xml:1,ffffffffffffffff"), format!("l{}", &description[1..]));
+        assert!(description.contains("<reg name=\"r15\" bitsize=\"8\" type=\"uint8\" regnum=\"15\"/>"));
+        assert!(description.contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" regnum=\"16\"/>"));
+        assert_eq!(reply(&mut stub, "qXfer:features:read:other.xml:0,20"), "E00");
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream: TcpStream = TcpStream::connect(address).unwrap();
+            // a corrupted packet is nacked, then the resend answered
+            stream.write_all(b"$p0#00$p0#a0").unwrap();
+            let mut replies: Vec<u8> = vec![0; 8];
+            stream.read_exact(&mut replies).unwrap();
+            stream.write_all(b"+$D#44").unwrap();
+            let mut detach: Vec<u8> = vec![0; 7];
+            stream.read_exact(&mut detach).unwrap();
+            stream.write_all(b"+").unwrap();
+            (replies, detach)
+        });
+
+        let mut stub: GdbStub = stub();
+        stub.get_cpu_mut().set_value_at_register(0, 0x2a);
+        stub.serve(listener.accept().unwrap().0).unwrap();
+        let (replies, detach) = client.join().unwrap();
+        assert_eq!(replies, b"-+$2a#93");
+        assert_eq!(detach, b"+$OK#9a");
+    }
+}
@@ -0,0 +1,566 @@
+use std::fmt;
+
+/*
+    opcode split into 4 parts:
+    - bits 15-12 represents operation
+        - 0: noop if 0x0111, terminate if 0x0
+            - 0x0001: return from interrupt handler
+            - 0x0002: enable interrupts
+            - 0x0003: disable interrupts
+        - 1: integer add involving 2 registers
+        - 2: integer add involving 1 register
+        - 3: bitwise OR involing 2 registers
+        - 4: bitwise OR involving 1 register
+        - 5: bitwise AND involving 2 registers
+        - 6: bitwise AND involving 1 register
+        - 7: Mov value at register Y into register X
+        - 8: Mov value into register X
+        - 9: Jump to memory address specified by bits 11-0
+        - 10: Save current address to stack and jump to memory address specified by bits 11-0
+        - 11: Jump to address stored at top of stack
+        - 12: Operation on register X and register Y, result stored in register X, bits 3-0 select the operation:
+            - 0: subtract register Y, carry is set on borrow
+            - 1: subtract register Y and the carry (borrow) flag
+            - 2: bitwise XOR with register Y
+            - 3: bitwise NOT of register Y
+            - 4: register Y shifted left by 1, carry gets bit 7
+            - 5: register Y shifted right by 1, carry gets bit 0
+            - 6: register Y rotated left by 1, carry gets bit 7
+            - 7: register Y rotated right by 1, carry gets bit 0
+            - 8: multiply by register Y, the high byte of the product is stored in register Y
+            - 9: unsigned divide by register Y
+            - 10: unsigned remainder of dividing by register Y
+        - 13: Skip the next instruction if a condition holds, bits 3-0 select the condition:
+            - 0: register X == register Y
+            - 1: register X != register Y
+            - 2: register X == value in bits 7-4
+            - 3: register X != value in bits 7-4
+            - 4: flag in bits 7-4 is set (0: zero, 1: carry, 2: overflow, 3: negative), bits 11-8 are 0
+            - 5: flag in bits 7-4 is clear, bits 11-8 are 0
+        - 14: Set index register I to the memory address specified by bits 11-0
+        - 15: Memory access through I, bits 3-0 select the operation:
+            - 0: load register X from address I + register Y
+            - 1: store register X at address I + register Y
+            - 2: load register X from address I, bits 7-4 are 0
+            - 3: store register X at address I, bits 7-4 are 0
+            - 4: load registers 0 to X from addresses I to I + X, bits 7-4 are 0
+            - 5: store registers 0 to X at addresses I to I + X, bits 7-4 are 0
+            - 6: add register X to I, bits 7-4 are 0
+    - bits 11-8 represent register X
+    - bits 7-4 represent register Y if operation involves 2 registers
+    - bits 7-4 represent value if operation involves 1 register
+    - bits 3-0 represent where to store result (register Z)
+*/
+
+/// A status flag, as named by the flag-testing skip instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Zero,
+    Carry,
+    Overflow,
+    Negative,
+}
+
+impl Flag {
+    fn from_nibble(nibble: u8) -> Option<Flag> {
+        match nibble {
+            0 => Some(Flag::Zero),
+            1 => Some(Flag::Carry),
+            2 => Some(Flag::Overflow),
+            3 => Some(Flag::Negative),
+            _ => None,
+        }
+    }
+
+    fn nibble(&self) -> u8 {
+        match self {
+            Flag::Zero => 0,
+            Flag::Carry => 1,
+            Flag::Overflow => 2,
+            Flag::Negative => 3,
+        }
+    }
+
+    /// The single-letter name used in assembly, e.g. `sfs c`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::Zero => "z",
+            Flag::Carry => "c",
+            Flag::Overflow => "v",
+            Flag::Negative => "n",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Flag> {
+        match name.to_ascii_lowercase().as_str() {
+            "z" => Some(Flag::Zero),
+            "c" => Some(Flag::Carry),
+            "v" => Some(Flag::Overflow),
+            "n" => Some(Flag::Negative),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded 16-bit opcode.
+///
+/// Register operands and immediate values are 4-bit nibbles, addresses are 12 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// `1XYZ`: register Z = register X + register Y
+    Add { x: u8, y: u8, z: u8 },
+    /// `2XNZ`: register Z = register X + N
+    AddImm { x: u8, value: u8, z: u8 },
+    /// `3XYZ`: register Z = register X | register Y
+    Or { x: u8, y: u8, z: u8 },
+    /// `4XNZ`: register Z = register X | N
+    OrImm { x: u8, value: u8, z: u8 },
+    /// `5XYZ`: register Z = register X & register Y
+    And { x: u8, y: u8, z: u8 },
+    /// `6XNZ`: register Z = register X & N
+    AndImm { x: u8, value: u8, z: u8 },
+    /// `7XY0`: register X = register Y
+    Mov { x: u8, y: u8 },
+    /// `8XN0`: register X = N
+    MovImm { x: u8, value: u8 },
+    /// `9NNN`: jump to address NNN
+    Jump { address: u16 },
+    /// `ANNN`: push the current address and jump to address NNN
+    Call { address: u16 },
+    /// `B000`: jump back to the address on top of the stack
+    Ret,
+    /// `CXY0`: register X = register X - register Y
+    Sub { x: u8, y: u8 },
+    /// `CXY1`: register X = register X - register Y - carry
+    SubBorrow { x: u8, y: u8 },
+    /// `CXY2`: register X = register X ^ register Y
+    Xor { x: u8, y: u8 },
+    /// `CXY3`: register X = !register Y
+    Not { x: u8, y: u8 },
+    /// `CXY4`: register X = register Y << 1
+    ShiftLeft { x: u8, y: u8 },
+    /// `CXY5`: register X = register Y >> 1
+    ShiftRight { x: u8, y: u8 },
+    /// `CXY6`: register X = register Y rotated left by 1
+    RotateLeft { x: u8, y: u8 },
+    /// `CXY7`: register X = register Y rotated right by 1
+    RotateRight { x: u8, y: u8 },
+    /// `CXY8`: register X = low byte of register X * register Y, register Y = high byte.
+    /// With X == Y only the high byte is kept.
+    Mul { x: u8, y: u8 },
+    /// `CXY9`: register X = register X / register Y
+    Div { x: u8, y: u8 },
+    /// `CXYA`: register X = register X % register Y
+    Mod { x: u8, y: u8 },
+    /// `DXY0`: skip the next instruction if register X == register Y
+    SkipEq { x: u8, y: u8 },
+    /// `DXY1`: skip the next instruction if register X != register Y
+    SkipNe { x: u8, y: u8 },
+    /// `DXN2`: skip the next instruction if register X == N
+    SkipEqImm { x: u8, value: u8 },
+    /// `DXN3`: skip the next instruction if register X != N
+    SkipNeImm { x: u8, value: u8 },
+    /// `D0F4`: skip the next instruction if the flag is set
+    SkipIfSet { flag: Flag },
+    /// `D0F5`: skip the next instruction if the flag is clear
+    SkipIfClear { flag: Flag },
+    /// `ENNN`: index register I = NNN
+    SetIndex { address: u16 },
+    /// `FXY0`: register X = memory[I + register Y]
+    LoadIndexed { x: u8, y: u8 },
+    /// `FXY1`: memory[I + register Y] = register X
+    StoreIndexed { x: u8, y: u8 },
+    /// `FX02`: register X = memory[I]
+    Load { x: u8 },
+    /// `FX03`: memory[I] = register X
+    Store { x: u8 },
+    /// `FX04`: registers 0 to X = memory[I] to memory[I + X]
+    LoadBlock { x: u8 },
+    /// `FX05`: memory[I] to memory[I + X] = registers 0 to X
+    StoreBlock { x: u8 },
+    /// `FX06`: I = I + register X
+    AddIndex { x: u8 },
+    /// `0001`: pop the interrupted address from the stack, jump to it and enable interrupts
+    ReturnFromInterrupt,
+    /// `0002`
+    EnableInterrupts,
+    /// `0003`
+    DisableInterrupts,
+    /// `0111`
+    Nop,
+    /// `0000`
+    Halt,
+    /// Any opcode that does not map to an operation.
+    Unknown(u16),
+}
+
+impl Instruction {
+    /// Every mnemonic [`Instruction::mnemonic`] can return for a decodable instruction.
+    pub const MNEMONICS: [&'static str; 38] = [
+        "add", "addi", "or", "ori", "and", "andi", "mov", "movi", "jmp", "call", "ret", "sub", "sbc", "xor", "not",
+        "shl", "shr", "rol", "ror", "mul", "div", "mod", "se", "sne", "sei", "snei", "sfs", "sfc", "ldi", "ld", "st",
+        "ldm", "stm", "reti", "ei", "di", "nop", "halt",
+    ];
+
+    pub fn decode(opcode: u16) -> Instruction {
+        let operation: u8 = (opcode >> 12) as u8;
+        let x: u8 = ((opcode >> 8) & 0xF) as u8;
+        let y: u8 = ((opcode >> 4) & 0xF) as u8;
+        let z: u8 = (opcode & 0xF) as u8;
+        let address: u16 = opcode & 0xFFF;
+
+        match operation {
+            0 if opcode == 0x0000 => Instruction::Halt,
+            0 if opcode == 0x0111 => Instruction::Nop,
+            0 if opcode == 0x0001 => Instruction::ReturnFromInterrupt,
+            0 if opcode == 0x0002 => Instruction::EnableInterrupts,
+            0 if opcode == 0x0003 => Instruction::DisableInterrupts,
+            1 => Instruction::Add { x, y, z },
+            2 => Instruction::AddImm { x, value: y, z },
+            3 => Instruction::Or { x, y, z },
+            4 => Instruction::OrImm { x, value: y, z },
+            5 => Instruction::And { x, y, z },
+            6 => Instruction::AndImm { x, value: y, z },
+            7 => Instruction::Mov { x, y },
+            8 => Instruction::MovImm { x, value: y },
+            9 => Instruction::Jump { address },
+            10 => Instruction::Call { address },
+            11 => Instruction::Ret,
+            12 => match z {
+                0 => Instruction::Sub { x, y },
+                1 => Instruction::SubBorrow { x, y },
+                2 => Instruction::Xor { x, y },
+                3 => Instruction::Not { x, y },
+                4 => Instruction::ShiftLeft { x, y },
+                5 => Instruction::ShiftRight { x, y },
+                6 => Instruction::RotateLeft { x, y },
+                7 => Instruction::RotateRight { x, y },
+                8 => Instruction::Mul { x, y },
+                9 => Instruction::Div { x, y },
+                10 => Instruction::Mod { x, y },
+                _ => Instruction::Unknown(opcode),
+            },
+            13 => match z {
+                0 => Instruction::SkipEq { x, y },
+                1 => Instruction::SkipNe { x, y },
+                2 => Instruction::SkipEqImm { x, value: y },
+                3 => Instruction::SkipNeImm { x, value: y },
+                4 | 5 => match Flag::from_nibble(y) {
+                    Some(flag) if x == 0 && z == 4 => Instruction::SkipIfSet { flag },
+                    Some(flag) if x == 0 => Instruction::SkipIfClear { flag },
+                    _ => Instruction::Unknown(opcode),
+                },
+                _ => Instruction::Unknown(opcode),
+            },
+            14 => Instruction::SetIndex { address },
+            15 => match (y, z) {
+                (_, 0) => Instruction::LoadIndexed { x, y },
+                (_, 1) => Instruction::StoreIndexed { x, y },
+                (0, 2) => Instruction::Load { x },
+                (0, 3) => Instruction::Store { x },
+                (0, 4) => Instruction::LoadBlock { x },
+                (0, 5) => Instruction::StoreBlock { x },
+                (0, 6) => Instruction::AddIndex { x },
+                _ => Instruction::Unknown(opcode),
+            },
+            _ => Instruction::Unknown(opcode),
+        }
+    }
+
+    /// The assembler mnemonic, the first word of the `Display` form. `add i, rX` shares `add`
+    /// with the register form, and unknown opcodes report `.word`.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Add { .. } | Instruction::AddIndex { .. } => "add",
+            Instruction::AddImm { .. } => "addi",
+            Instruction::Or { .. } => "or",
+            Instruction::OrImm { .. } => "ori",
+            Instruction::And { .. } => "and",
+            Instruction::AndImm { .. } => "andi",
+            Instruction::Mov { .. } => "mov",
+            Instruction::MovImm { .. } => "movi",
+            Instruction::Jump { .. } => "jmp",
+            Instruction::Call { .. } => "call",
+            Instruction::Ret => "ret",
+            Instruction::Sub { .. } => "sub",
+            Instruction::SubBorrow { .. } => "sbc",
+            Instruction::Xor { .. } => "xor",
+            Instruction::Not { .. } => "not",
+            Instruction::ShiftLeft { .. } => "shl",
+            Instruction::ShiftRight { .. } => "shr",
+            Instruction::RotateLeft { .. } => "rol",
+            Instruction::RotateRight { .. } => "ror",
+            Instruction::Mul { .. } => "mul",
+            Instruction::Div { .. } => "div",
+            Instruction::Mod { .. } => "mod",
+            Instruction::SkipEq { .. } => "se",
+            Instruction::SkipNe { .. } => "sne",
+            Instruction::SkipEqImm { .. } => "sei",
+            Instruction::SkipNeImm { .. } => "snei",
+            Instruction::SkipIfSet { .. } => "sfs",
+            Instruction::SkipIfClear { .. } => "sfc",
+            Instruction::SetIndex { .. } => "ldi",
+            Instruction::LoadIndexed { .. } | Instruction::Load { .. } => "ld",
+            Instruction::StoreIndexed { .. } | Instruction::Store { .. } => "st",
+            Instruction::LoadBlock { .. } => "ldm",
+            Instruction::StoreBlock { .. } => "stm",
+            Instruction::ReturnFromInterrupt => "reti",
+            Instruction::EnableInterrupts => "ei",
+            Instruction::DisableInterrupts => "di",
+            Instruction::Nop => "nop",
+            Instruction::Halt => "halt",
+            Instruction::Unknown(_) => ".word",
+        }
+    }
+
+    /// Registers the instruction reads, one bit per register.
+    pub fn register_reads(&self) -> u16 {
+        let bit = |register: u8| 1_u16 << register;
+        match *self {
+            Instruction::Add { x, y, .. } | Instruction::Or { x, y, .. } | Instruction::And { x, y, .. } => bit(x) | bit(y),
+            Instruction::AddImm { x, .. } | Instruction::OrImm { x, .. } | Instruction::AndImm { x, .. } => bit(x),
+            Instruction::Mov { y, .. }
+            | Instruction::Not { y, .. }
+            | Instruction::ShiftLeft { y, .. }
+            | Instruction::ShiftRight { y, .. }
+            | Instruction::RotateLeft { y, .. }
+            | Instruction::RotateRight { y, .. }
+            | Instruction::LoadIndexed { y, .. } => bit(y),
+            Instruction::Sub { x, y }
+            | Instruction::SubBorrow { x, y }
+            | Instruction::Xor { x, y }
+            | Instruction::Mul { x, y }
+            | Instruction::Div { x, y }
+            | Instruction::Mod { x, y }
+            | Instruction::SkipEq { x, y }
+            | Instruction::SkipNe { x, y }
+            | Instruction::StoreIndexed { x, y } => bit(x) | bit(y),
+            Instruction::SkipEqImm { x, .. } | Instruction::SkipNeImm { x, .. } | Instruction::Store { x } | Instruction::AddIndex { x } => {
+                bit(x)
+            }
+            Instruction::StoreBlock { x } => (bit(x) << 1).wrapping_sub(1),
+            _ => 0,
+        }
+    }
+
+    /// Registers the instruction writes, one bit per register.
+    pub fn register_writes(&self) -> u16 {
+        let bit = |register: u8| 1_u16 << register;
+        match *self {
+            Instruction::Add { z, .. }
+            | Instruction::AddImm { z, .. }
+            | Instruction::Or { z, .. }
+            | Instruction::OrImm { z, .. }
+            | Instruction::And { z, .. }
+            | Instruction::AndImm { z, .. } => bit(z),
+            Instruction::Mov { x, .. }
+            | Instruction::MovImm { x, .. }
+            | Instruction::Sub { x, .. }
+            | Instruction::SubBorrow { x, .. }
+            | Instruction::Xor { x, .. }
+            | Instruction::Not { x, .. }
+            | Instruction::ShiftLeft { x, .. }
+            | Instruction::ShiftRight { x, .. }
+            | Instruction::RotateLeft { x, .. }
+            | Instruction::RotateRight { x, .. }
+            | Instruction::Div { x, .. }
+            | Instruction::Mod { x, .. }
+            | Instruction::LoadIndexed { x, .. }
+            | Instruction::Load { x } => bit(x),
+            Instruction::Mul { x, y } => bit(x) | bit(y),
+            Instruction::LoadBlock { x } => (bit(x) << 1).wrapping_sub(1),
+            _ => 0,
+        }
+    }
+
+    /// Inverse of [`Instruction::decode`]. Nibbles an operation ignores are encoded as 0,
+    /// so `decode(op).encode()` is only guaranteed to equal `op` for canonical opcodes.
+    pub fn encode(&self) -> u16 {
+        match *self {
+            Instruction::Halt => 0x0000,
+            Instruction::Nop => 0x0111,
+            Instruction::ReturnFromInterrupt => 0x0001,
+            Instruction::EnableInterrupts => 0x0002,
+            Instruction::DisableInterrupts => 0x0003,
+            Instruction::Add { x, y, z } => nibbles(0x1, x, y, z),
+            Instruction::AddImm { x, value, z } => nibbles(0x2, x, value, z),
+            Instruction::Or { x, y, z } => nibbles(0x3, x, y, z),
+            Instruction::OrImm { x, value, z } => nibbles(0x4, x, value, z),
+            Instruction::And { x, y, z } => nibbles(0x5, x, y, z),
+            Instruction::AndImm { x, value, z } => nibbles(0x6, x, value, z),
+            Instruction::Mov { x, y } => nibbles(0x7, x, y, 0),
+            Instruction::MovImm { x, value } => nibbles(0x8, x, value, 0),
+            Instruction::Jump { address } => 0x9000 | (address & 0xFFF),
+            Instruction::Call { address } => 0xA000 | (address & 0xFFF),
+            Instruction::Ret => 0xB000,
+            Instruction::Sub { x, y } => nibbles(0xC, x, y, 0),
+            Instruction::SubBorrow { x, y } => nibbles(0xC, x, y, 1),
+            Instruction::Xor { x, y } => nibbles(0xC, x, y, 2),
+            Instruction::Not { x, y } => nibbles(0xC, x, y, 3),
+            Instruction::ShiftLeft { x, y } => nibbles(0xC, x, y, 4),
+            Instruction::ShiftRight { x, y } => nibbles(0xC, x, y, 5),
+            Instruction::RotateLeft { x, y } => nibbles(0xC, x, y, 6),
+            Instruction::RotateRight { x, y } => nibbles(0xC, x, y, 7),
+            Instruction::Mul { x, y } => nibbles(0xC, x, y, 8),
+            Instruction::Div { x, y } => nibbles(0xC, x, y, 9),
+            Instruction::Mod { x, y } => nibbles(0xC, x, y, 10),
+            Instruction::SkipEq { x, y } => nibbles(0xD, x, y, 0),
+            Instruction::SkipNe { x, y } => nibbles(0xD, x, y, 1),
+            Instruction::SkipEqImm { x, value } => nibbles(0xD, x, value, 2),
+            Instruction::SkipNeImm { x, value } => nibbles(0xD, x, value, 3),
+            Instruction::SkipIfSet { flag } => nibbles(0xD, 0, flag.nibble(), 4),
+            Instruction::SkipIfClear { flag } => nibbles(0xD, 0, flag.nibble(), 5),
+            Instruction::SetIndex { address } => 0xE000 | (address & 0xFFF),
+            Instruction::LoadIndexed { x, y } => nibbles(0xF, x, y, 0),
+            Instruction::StoreIndexed { x, y } => nibbles(0xF, x, y, 1),
+            Instruction::Load { x } => nibbles(0xF, x, 0, 2),
+            Instruction::Store { x } => nibbles(0xF, x, 0, 3),
+            Instruction::LoadBlock { x } => nibbles(0xF, x, 0, 4),
+            Instruction::StoreBlock { x } => nibbles(0xF, x, 0, 5),
+            Instruction::AddIndex { x } => nibbles(0xF, x, 0, 6),
+            Instruction::Unknown(opcode) => opcode,
+        }
+    }
+}
+
+fn nibbles(operation: u8, x: u8, y: u8, z: u8) -> u16 {
+    ((operation as u16 & 0xF) << 12) | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4) | (z as u16 & 0xF)
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Add { x, y, z } => write!(f, "add r{}, r{} -> r{}", x, y, z),
+            Instruction::AddImm { x, value, z } => write!(f, "addi r{}, 0x{:x} -> r{}", x, value, z),
+            Instruction::Or { x, y, z } => write!(f, "or r{}, r{} -> r{}", x, y, z),
+            Instruction::OrImm { x, value, z } => write!(f, "ori r{}, 0x{:x} -> r{}", x, value, z),
+            Instruction::And { x, y, z } => write!(f, "and r{}, r{} -> r{}", x, y, z),
+            Instruction::AndImm { x, value, z } => write!(f, "andi r{}, 0x{:x} -> r{}", x, value, z),
+            Instruction::Mov { x, y } => write!(f, "mov r{}, r{}", x, y),
+            Instruction::MovImm { x, value } => write!(f, "movi r{}, 0x{:x}", x, value),
+            Instruction::Jump { address } => write!(f, "jmp 0x{:03x}", address),
+            Instruction::Call { address } => write!(f, "call 0x{:03x}", address),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Sub { x, y } => write!(f, "sub r{}, r{}", x, y),
+            Instruction::SubBorrow { x, y } => write!(f, "sbc r{}, r{}", x, y),
+            Instruction::Xor { x, y } => write!(f, "xor r{}, r{}", x, y),
+            Instruction::Not { x, y } => write!(f, "not r{}, r{}", x, y),
+            Instruction::ShiftLeft { x, y } => write!(f, "shl r{}, r{}", x, y),
+            Instruction::ShiftRight { x, y } => write!(f, "shr r{}, r{}", x, y),
+            Instruction::RotateLeft { x, y } => write!(f, "rol r{}, r{}", x, y),
+            Instruction::RotateRight { x, y } => write!(f, "ror r{}, r{}", x, y),
+            Instruction::Mul { x, y } => write!(f, "mul r{}, r{}", x, y),
+            Instruction::Div { x, y } => write!(f, "div r{}, r{}", x, y),
+            Instruction::Mod { x, y } => write!(f, "mod r{}, r{}", x, y),
+            Instruction::SkipEq { x, y } => write!(f, "se r{}, r{}", x, y),
+            Instruction::SkipNe { x, y } => write!(f, "sne r{}, r{}", x, y),
+            Instruction::SkipEqImm { x, value } => write!(f, "sei r{}, 0x{:x}", x, value),
+            Instruction::SkipNeImm { x, value } => write!(f, "snei r{}, 0x{:x}", x, value),
+            Instruction::SkipIfSet { flag } => write!(f, "sfs {}", flag.name()),
+            Instruction::SkipIfClear { flag } => write!(f, "sfc {}", flag.name()),
+            Instruction::SetIndex { address } => write!(f, "ldi 0x{:03x}", address),
+            Instruction::LoadIndexed { x, y } => write!(f, "ld r{}, [i + r{}]", x, y),
+            Instruction::StoreIndexed { x, y } => write!(f, "st r{}, [i + r{}]", x, y),
+            Instruction::Load { x } => write!(f, "ld r{}, [i]", x),
+            Instruction::Store { x } => write!(f, "st r{}, [i]", x),
+            Instruction::LoadBlock { x } => write!(f, "ldm r{}", x),
+            Instruction::StoreBlock { x } => write!(f, "stm r{}", x),
+            Instruction::AddIndex { x } => write!(f, "add i, r{}", x),
+            Instruction::ReturnFromInterrupt => write!(f, "reti"),
+            Instruction::EnableInterrupts => write!(f, "ei"),
+            Instruction::DisableInterrupts => write!(f, "di"),
+            Instruction::Nop => write!(f, "nop"),
+            Instruction::Halt => write!(f, "halt"),
+            Instruction::Unknown(opcode) => write!(f, ".word 0x{:04x}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Instruction::decode(0x1012), Instruction::Add { x: 0, y: 1, z: 2 });
+        assert_eq!(Instruction::decode(0x40F2), Instruction::OrImm { x: 0, value: 0xF, z: 2 });
+        assert_eq!(Instruction::decode(0x701F), Instruction::Mov { x: 0, y: 1 });
+        assert_eq!(Instruction::decode(0xA100), Instruction::Call { address: 0x100 });
+        assert_eq!(Instruction::decode(0x0111), Instruction::Nop);
+        assert_eq!(Instruction::decode(0x0000), Instruction::Halt);
+        assert_eq!(Instruction::decode(0x0001), Instruction::ReturnFromInterrupt);
+        assert_eq!(Instruction::decode(0x0003), Instruction::DisableInterrupts);
+        assert_eq!(Instruction::decode(0x0004), Instruction::Unknown(0x0004));
+        assert_eq!(Instruction::decode(0x0123), Instruction::Unknown(0x0123));
+        assert_eq!(Instruction::decode(0xC120), Instruction::Sub { x: 1, y: 2 });
+        assert_eq!(Instruction::decode(0xC127), Instruction::RotateRight { x: 1, y: 2 });
+        assert_eq!(Instruction::decode(0xC128), Instruction::Mul { x: 1, y: 2 });
+        assert_eq!(Instruction::decode(0xC12A), Instruction::Mod { x: 1, y: 2 });
+        assert_eq!(Instruction::decode(0xC12F), Instruction::Unknown(0xC12F));
+        assert_eq!(Instruction::decode(0xD121), Instruction::SkipNe { x: 1, y: 2 });
+        assert_eq!(Instruction::decode(0xD0F2), Instruction::SkipEqImm { x: 0, value: 0xF });
+        assert_eq!(Instruction::decode(0xD014), Instruction::SkipIfSet { flag: Flag::Carry });
+        assert_eq!(Instruction::decode(0xD035), Instruction::SkipIfClear { flag: Flag::Negative });
+        assert_eq!(Instruction::decode(0xD044), Instruction::Unknown(0xD044));
+        assert_eq!(Instruction::decode(0xD104), Instruction::Unknown(0xD104));
+        assert_eq!(Instruction::decode(0xD006), Instruction::Unknown(0xD006));
+        assert_eq!(Instruction::decode(0xE123), Instruction::SetIndex { address: 0x123 });
+        assert_eq!(Instruction::decode(0xF230), Instruction::LoadIndexed { x: 2, y: 3 });
+        assert_eq!(Instruction::decode(0xF205), Instruction::StoreBlock { x: 2 });
+        assert_eq!(Instruction::decode(0xF215), Instruction::Unknown(0xF215));
+        assert_eq!(Instruction::decode(0xF207), Instruction::Unknown(0xF207));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        for opcode in 0..=0xFFFF_u16 {
+            let instruction = Instruction::decode(opcode);
+            assert_eq!(Instruction::decode(instruction.encode()), instruction);
+        }
+        assert_eq!(Instruction::decode(0x1234).encode(), 0x1234);
+        assert_eq!(Instruction::decode(0x701F).encode(), 0x7010);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instruction::decode(0x1012).to_string(), "add r0, r1 -> r2");
+        assert_eq!(Instruction::decode(0x40F2).to_string(), "ori r0, 0xf -> r2");
+        assert_eq!(Instruction::decode(0x8AF0).to_string(), "movi r10, 0xf");
+        assert_eq!(Instruction::decode(0x9026).to_string(), "jmp 0x026");
+        assert_eq!(Instruction::decode(0x0FFF).to_string(), ".word 0x0fff");
+        assert_eq!(Instruction::decode(0xC3A1).to_string(), "sbc r3, r10");
+        assert_eq!(Instruction::decode(0xD3A2).to_string(), "sei r3, 0xa");
+        assert_eq!(Instruction::decode(0xD005).to_string(), "sfc z");
+        assert_eq!(Instruction::decode(0xE0F0).to_string(), "ldi 0x0f0");
+        assert_eq!(Instruction::decode(0xF1A1).to_string(), "st r1, [i + r10]");
+        assert_eq!(Instruction::decode(0xF102).to_string(), "ld r1, [i]");
+        assert_eq!(Instruction::decode(0xF304).to_string(), "ldm r3");
+        assert_eq!(Instruction::decode(0xF406).to_string(), "add i, r4");
+    }
+
+    #[test]
+    fn test_register_accesses() {
+        let add: Instruction = Instruction::decode(0x1012);
+        assert_eq!((add.register_reads(), add.register_writes()), (0b011, 0b100));
+        let mul: Instruction = Instruction::decode(0xC128);
+        assert_eq!((mul.register_reads(), mul.register_writes()), (0b110, 0b110));
+        let store: Instruction = Instruction::decode(0xF1A1);
+        assert_eq!((store.register_reads(), store.register_writes()), (0x0402, 0));
+        let load_all: Instruction = Instruction::decode(0xFF04);
+        assert_eq!((load_all.register_reads(), load_all.register_writes()), (0, 0xFFFF));
+        assert_eq!(Instruction::decode(0xF205).register_reads(), 0b111);
+        assert_eq!(Instruction::decode(0xA100).register_writes(), 0);
+    }
+
+    #[test]
+    fn test_mnemonic() {
+        for opcode in 0..=0xFFFF {
+            let instruction: Instruction = Instruction::decode(opcode);
+            let text: String = instruction.to_string();
+            assert_eq!(text.split(' ').next(), Some(instruction.mnemonic()), "{}", text);
+            if !matches!(instruction, Instruction::Unknown(_)) {
+                assert!(Instruction::MNEMONICS.contains(&instruction.mnemonic()));
+            }
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use std::error::Error;
+use std::fmt;
+
+/// A JSON value, just enough for the debug adapter. Objects keep their keys in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Why a JSON text did not parse. `offset` is the byte offset of the problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for JsonError {}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, JsonError> {
+        let mut parser: Parser = Parser { bytes: text.as_bytes(), position: 0 };
+        let value: Json = parser.value()?;
+        parser.whitespace();
+        if parser.position < parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    /// An object from `(key, value)` pairs.
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    /// The member `key` of an object; `None` for a missing key or a non-object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value of an integral number.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(value) if value.fract() == 0.0 && value.abs() < 9.0e15 => Some(*value as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Json {
+        Json::Number(value as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(text: &str) -> Json {
+        Json::String(text.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(text: String) -> Json {
+        Json::String(text)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Json {
+        Json::Array(values)
+    }
+}
+
+/// Compact JSON text, without whitespace.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) if !value.is_finite() => write!(f, "null"),
+            Json::Number(value) if value.fract() == 0.0 && value.abs() < 9.0e15 => write!(f, "{}", *value as i64),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::String(text) => write_string(f, text),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> JsonError {
+        JsonError { offset: self.position, message: message.to_string() }
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.position) {
+            self.position += 1;
+        }
+    }
+
+    // consumes `byte` after optional whitespace
+    fn eat(&mut self, byte: u8) -> bool {
+        self.whitespace();
+        if self.bytes.get(self.position) == Some(&byte) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        if !self.bytes[self.position..].starts_with(word.as_bytes()) {
+            return Err(self.error("expected a value"));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.whitespace();
+        match self.bytes.get(self.position) {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.position += 1;
+                let mut values: Vec<Json> = Vec::new();
+                if self.eat(b']') {
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    if self.eat(b']') {
+                        return Ok(Json::Array(values));
+                    }
+                    if !self.eat(b',') {
+                        return Err(self.error("expected `,` or `]`"));
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.position += 1;
+                let mut members: Vec<(String, Json)> = Vec::new();
+                if self.eat(b'}') {
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    if self.bytes.get(self.position) != Some(&b'"') {
+                        return Err(self.error("expected a key"));
+                    }
+                    let key: String = self.string()?;
+                    if !self.eat(b':') {
+                        return Err(self.error("expected `:`"));
+                    }
+                    members.push((key, self.value()?));
+                    if self.eat(b'}') {
+                        return Ok(Json::Object(members));
+                    }
+                    if !self.eat(b',') {
+                        return Err(self.error("expected `,` or `}`"));
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of text")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start: usize = self.position;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.bytes.get(self.position) {
+            self.position += 1;
+        }
+        // the scanned bytes are all ASCII
+        let text: &str = std::str::from_utf8(&self.bytes[start..self.position]).unwrap();
+        text.parse::<f64>().map(Json::Number).map_err(|_| JsonError { offset: start, message: format!("invalid number `{}`", text) })
+    }
+
+    // a string starting at the opening quote
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.position += 1;
+        let mut text: Vec<u8> = Vec::new();
+        loop {
+            match self.bytes.get(self.position) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.position += 1;
+                    let unescaped: char = match self.bytes.get(self.position) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buffer: [u8; 4] = [0; 4];
+                    text.extend_from_slice(unescaped.encode_utf8(&mut buffer).as_bytes());
+                }
+                Some(byte) => text.push(*byte),
+            }
+            self.position += 1;
+        }
+        self.position += 1;
+        // the input was a &str and escapes produce whole characters
+        Ok(String::from_utf8(text).unwrap())
+    }
+
+    // `uXXXX` at the current position, possibly the first half of a surrogate pair; leaves the
+    // position on the last hex digit
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let first: u32 = self.hex4()?;
+        if (0xD800..0xDC00).contains(&first) && self.bytes[self.position + 1..].starts_with(b"\\u") {
+            self.position += 2;
+            let second: u32 = self.hex4()?;
+            if (0xDC00..0xE000).contains(&second) {
+                let code: u32 = 0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00);
+                return char::from_u32(code).ok_or_else(|| self.error("invalid escape"));
+            }
+        }
+        Ok(char::from_u32(first).unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits: &[u8] = self.bytes.get(self.position + 1..self.position + 5).ok_or_else(|| self.error("invalid escape"))?;
+        let value: u32 = std::str::from_utf8(digits).ok().and_then(|digits| u32::from_str_radix(digits, 16).ok()).ok_or_else(|| self.error("invalid escape"))?;
+        self.position += 4;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let value: Json = Json::parse(r#" {"seq": 1, "args": {"lines": [3, -4.5e1], "ok": true, "none": null}, "text": "a\"\né😀"} "#).unwrap();
+        assert_eq!(value.get("seq").and_then(Json::as_i64), Some(1));
+        let args: &Json = value.get("args").unwrap();
+        assert_eq!(args.get("lines").and_then(Json::as_array), Some(&[Json::Number(3.0), Json::Number(-45.0)][..]));
+        assert_eq!(args.get("ok").and_then(Json::as_bool), Some(true));
+        assert_eq!(args.get("none"), Some(&Json::Null));
+        assert_eq!(value.get("text").and_then(Json::as_str), Some("a\"\n\u{e9}\u{1F600}"));
+        assert_eq!(value.get("missing"), None);
+
+        assert_eq!(Json::parse("[1, 2").unwrap_err().message, "expected `,` or `]`");
+        assert_eq!(Json::parse("{\"a\" 1}").unwrap_err(), JsonError { offset: 5, message: "expected `:`".to_string() });
+        assert!(Json::parse("\"open").is_err());
+        assert!(Json::parse("1 2").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let value: Json = Json::object(vec![
+            ("name", Json::from("r0 \"a\"\n")),
+            ("values", Json::from(vec![Json::from(42), Json::Number(0.5), Json::from(false), Json::Null])),
+            ("empty", Json::object(vec![])),
+        ]);
+        let text: String = value.to_string();
+        assert_eq!(text, r#"{"name":"r0 \"a\"\n","values":[42,0.5,false,null],"empty":{}}"#);
+        assert_eq!(Json::parse(&text).unwrap(), value);
+    }
+}
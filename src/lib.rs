@@ -0,0 +1,15 @@
+pub mod assembler;
+pub mod bus;
+pub mod console;
+pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod disassembler;
+pub mod expression;
+pub mod gdb;
+pub mod instruction;
+pub mod json;
+pub mod snapshot;
+pub mod timer;
+pub mod timing;
+pub mod trace;
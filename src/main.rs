@@ -1,5 +1,307 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpu_emulator::assembler::{self, Program};
+use cpu_emulator::bus::{Bus, MemoryMap, ADDRESS_SPACE};
+use cpu_emulator::console::Console;
+use cpu_emulator::cpu::{Budget, Cpu, CpuError, HaltReason};
+use cpu_emulator::dap;
+use cpu_emulator::debugger::Debugger;
+use cpu_emulator::disassembler;
+use cpu_emulator::gdb::GdbStub;
+use cpu_emulator::trace::{BinaryTracer, TextTracer, Tracer};
+
+const USAGE: &str = "usage:
+    cpu-emulator run (<program.bin> [--origin <address>] | --load-state <state>) [--save-state <state>]
+                     [--clock <hz>] [--max-instructions <n> | --max-cycles <n>]
+                     [--trace <file | -> [--trace-format <text | binary>]]
+    cpu-emulator debug <program.bin | source.s> [--origin <address>] [--input <file>]
+    cpu-emulator gdb <program.bin | source.s> [--origin <address>] [--input <file>] [--port <port>]
+    cpu-emulator dap
+    cpu-emulator asm <source.s> [-o <program.bin>]
+    cpu-emulator disasm <program.bin> [--origin <address>] [--start <address>] [--end <address>]";
 
-mod cpu;
 fn main() {
-    let cpu: cpu::Cpu = cpu::Cpu::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result: Result<(), String> = match args.first().map(String::as_str) {
+        Some("run") => run(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("gdb") => gdb(&args[1..]),
+        // the program comes from the editor's launch request
+        Some("dap") if args.len() == 1 => dap::serve(io::BufReader::new(io::stdin()), io::stdout()).map_err(|e| e.to_string()),
+        Some("asm") => asm(&args[1..]),
+        Some("disasm") => disasm(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let mut path: Option<&String> = None;
+    let mut origin: u16 = 0;
+    let mut clock: Option<u64> = None;
+    let mut budget: Option<Budget> = None;
+    let mut load_state: Option<&String> = None;
+    let mut save_state: Option<&String> = None;
+    let mut trace: Option<&String> = None;
+    let mut trace_format: &str = "text";
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--origin" => origin = parse_address(args.next().ok_or(USAGE)?)?,
+            "--clock" => clock = Some(parse_frequency(args.next().ok_or(USAGE)?)?),
+            "--max-instructions" => budget = Some(Budget::Instructions(parse_count(args.next().ok_or(USAGE)?)?)),
+            "--max-cycles" => budget = Some(Budget::Cycles(parse_count(args.next().ok_or(USAGE)?)?)),
+            "--load-state" => load_state = Some(args.next().ok_or(USAGE)?),
+            "--save-state" => save_state = Some(args.next().ok_or(USAGE)?),
+            "--trace" => trace = Some(args.next().ok_or(USAGE)?),
+            "--trace-format" => trace_format = args.next().ok_or(USAGE)?,
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let mut cpu: Cpu = Cpu::new();
+    match (path, load_state) {
+        (Some(path), None) => {
+            let program: Vec<u8> = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            cpu.load_program(&program, origin).map_err(|e| format!("{}: {}", path, e))?;
+            cpu.set_program_counter(origin);
+        }
+        (None, Some(state)) => {
+            let bytes: Vec<u8> = fs::read(state).map_err(|e| format!("{}: {}", state, e))?;
+            cpu.load_state(&bytes).map_err(|e| format!("{}: {}", state, e))?;
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    // budgets given on the command line apply to this run only, even over a restored state
+    cpu.set_budget(budget);
+    if let Some(trace) = trace {
+        cpu.set_tracer(Some(open_tracer(trace, trace_format)?));
+    }
+    let result = match clock {
+        Some(hz) => run_throttled(&mut cpu, hz),
+        None => cpu.run(),
+    };
+    cpu.set_tracer(None); // flushes the trace before the registers are printed
+
+    print_registers(&cpu);
+    // saved even after a fault, so the state can be attached to a bug report
+    if let Some(state) = save_state {
+        fs::write(state, cpu.save_state()).map_err(|e| format!("{}: {}", state, e))?;
+    }
+    match result {
+        Ok(HaltReason::BudgetExhausted) => Err(format!("budget exhausted at 0x{:03x}", cpu.get_program_counter())),
+        Ok(_) => Ok(()), // `run` sets no breakpoints, watchpoints or conditions
+        Err(fault) => Err(format!("fault: {}", fault)),
+    }
+}
+
+// the REPL owns stdin, so the guest console reads from `--input` or sees no input at all
+fn debug(args: &[String]) -> Result<(), String> {
+    let mut path: Option<&String> = None;
+    let mut origin: u16 = 0;
+    let mut input: Option<&String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--origin" => origin = parse_address(args.next().ok_or(USAGE)?)?,
+            "--input" => input = Some(args.next().ok_or(USAGE)?),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let path: &String = path.ok_or(USAGE)?;
+    let (cpu, labels) = load_debuggee(path, origin, input)?;
+    let mut debugger: Debugger = Debugger::new(cpu, labels);
+    debugger.repl(&mut io::stdin().lock(), &mut io::stdout()).map_err(|e| e.to_string())
+}
+
+fn gdb(args: &[String]) -> Result<(), String> {
+    let mut path: Option<&String> = None;
+    let mut origin: u16 = 0;
+    let mut input: Option<&String> = None;
+    let mut port: u16 = 1234;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--origin" => origin = parse_address(args.next().ok_or(USAGE)?)?,
+            "--input" => input = Some(args.next().ok_or(USAGE)?),
+            "--port" => {
+                let text: &String = args.next().ok_or(USAGE)?;
+                port = text.parse::<u16>().map_err(|_| format!("invalid port `{}`", text))?;
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let path: &String = path.ok_or(USAGE)?;
+    let (cpu, _) = load_debuggee(path, origin, input)?;
+
+    // local connections only: the protocol has no authentication
+    let listener: TcpListener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("port {}: {}", port, e))?;
+    eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+    let (stream, peer) = listener.accept().map_err(|e| e.to_string())?;
+    eprintln!("gdb connected from {}", peer);
+    GdbStub::new(cpu).serve(stream).map_err(|e| e.to_string())
+}
+
+/// Loads a program for `debug` or `gdb`, with pc at its origin. Sources are assembled so their
+/// labels can be used as locations. The guest's console input is `input`, or empty.
+fn load_debuggee(path: &str, origin: u16, input: Option<&String>) -> Result<(Cpu, BTreeMap<String, u16>), String> {
+    let (bytes, origin, labels) = if path.ends_with(".s") {
+        let source: String = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let program: Program = assembler::assemble(&source).map_err(|e| format!("{}:{}", path, e))?;
+        (program.bytes, program.origin, program.labels)
+    } else {
+        (fs::read(path).map_err(|e| format!("{}: {}", path, e))?, origin, Default::default())
+    };
+    let guest_input: Box<dyn io::Read> = match input {
+        Some(input) => Box::new(fs::File::open(input).map_err(|e| format!("{}: {}", input, e))?),
+        None => Box::new(io::empty()),
+    };
+
+    let mut cpu: Cpu = Cpu::with_bus(MemoryMap::standard(Console::new(guest_input, Box::new(io::stdout()))));
+    cpu.load_program(&bytes, origin).map_err(|e| format!("{}: {}", path, e))?;
+    cpu.set_program_counter(origin);
+    Ok((cpu, labels))
+}
+
+/// Opens a tracer writing to `path`, or to stderr for `-`.
+fn open_tracer(path: &str, format: &str) -> Result<Box<dyn Tracer>, String> {
+    let output: Box<dyn Write> = match path {
+        "-" => Box::new(io::stderr()),
+        _ => Box::new(fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?),
+    };
+    let output: BufWriter<Box<dyn Write>> = BufWriter::new(output);
+    match format {
+        "text" => Ok(Box::new(TextTracer::new(output))),
+        "binary" => Ok(Box::new(BinaryTracer::new(output))),
+        _ => Err(format!("unknown trace format `{}`", format)),
+    }
+}
+
+/// Runs `cpu` at `hz` cycles per second of wall-clock time, sleeping between 10 ms slices.
+fn run_throttled(cpu: &mut Cpu, hz: u64) -> Result<HaltReason, CpuError> {
+    let start: Instant = Instant::now();
+    let first_cycle: u64 = cpu.get_cycles();
+    let slice: u64 = (hz / 100).max(1);
+    loop {
+        if let Some(reason) = cpu.run_for_cycles(slice)? {
+            return Ok(reason);
+        }
+        let due: Duration = Duration::from_secs_f64((cpu.get_cycles() - first_cycle) as f64 / hz as f64);
+        if let Some(ahead) = due.checked_sub(start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+fn asm(args: &[String]) -> Result<(), String> {
+    let mut path: Option<&String> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(args.next().ok_or(USAGE)?)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let path: &String = path.ok_or(USAGE)?;
+    let output: PathBuf = output.unwrap_or_else(|| PathBuf::from(path).with_extension("bin"));
+
+    let source: String = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let program: Program = assembler::assemble(&source).map_err(|e| format!("{}:{}", path, e))?;
+    fs::write(&output, &program.bytes).map_err(|e| format!("{}: {}", output.display(), e))?;
+    eprintln!("wrote {} bytes to {} (origin 0x{:03x})", program.bytes.len(), output.display(), program.origin);
+    Ok(())
+}
+
+fn disasm(args: &[String]) -> Result<(), String> {
+    let mut path: Option<&String> = None;
+    let mut origin: u16 = 0;
+    let mut start: Option<u16> = None;
+    let mut end: Option<u16> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--origin" => origin = parse_address(args.next().ok_or(USAGE)?)?,
+            "--start" => start = Some(parse_address(args.next().ok_or(USAGE)?)?),
+            "--end" => end = Some(parse_address(args.next().ok_or(USAGE)?)?),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let path: &String = path.ok_or(USAGE)?;
+    let program: Vec<u8> = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut cpu: Cpu = Cpu::new();
+    cpu.load_program(&program, origin).map_err(|e| format!("{}: {}", path, e))?;
+    let start: usize = start.unwrap_or(origin) as usize;
+    let end: usize = end.map_or(origin as usize + program.len(), |end| end as usize);
+    if start > end || end > ADDRESS_SPACE {
+        return Err(format!("invalid range 0x{:03x}..0x{:03x}", start, end));
+    }
+    let memory: Vec<u8> = cpu.get_bus().peek_range(start as u16, end - start);
+    for line in disassembler::disassemble(&memory, start as u16) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+// stderr, so stdout carries only what the guest program writes to the console
+fn print_registers(cpu: &Cpu) {
+    for row in 0..4 {
+        let line: Vec<String> = (0..4)
+            .map(|col| row * 4 + col)
+            .map(|register| format!("r{:<2} = 0x{:02x}", register, cpu.get_value_at_register(register)))
+            .collect();
+        eprintln!("{}", line.join("  "));
+    }
+    eprintln!(
+        "pc  = 0x{:03x}  i = 0x{:03x}  flags = {}  cycles = {}",
+        cpu.get_program_counter(),
+        cpu.get_index(),
+        cpu.get_flags(),
+        cpu.get_cycles()
+    );
+}
+
+/// Parses a `0x`-prefixed hex or plain decimal address.
+fn parse_address(text: &str) -> Result<u16, String> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse::<u16>(),
+    };
+    parsed.map_err(|_| format!("invalid address `{}`", text))
+}
+
+fn parse_count(text: &str) -> Result<u64, String> {
+    text.parse::<u64>().map_err(|_| format!("invalid count `{}`", text))
+}
+
+/// Parses a clock frequency in Hz, with an optional `k` or `M` multiplier suffix.
+fn parse_frequency(text: &str) -> Result<u64, String> {
+    let (digits, multiplier): (&str, u64) = match text.strip_suffix('k') {
+        Some(digits) => (digits, 1_000),
+        None => match text.strip_suffix('M') {
+            Some(digits) => (digits, 1_000_000),
+            None => (text, 1),
+        },
+    };
+    match digits.parse::<u64>().ok().and_then(|hz| hz.checked_mul(multiplier)) {
+        Some(hz) if hz > 0 => Ok(hz),
+        _ => Err(format!("invalid clock frequency `{}`", text)),
+    }
 }
@@ -0,0 +1,171 @@
+use std::error::Error;
+use std::fmt;
+
+/*
+    save state format, all integers big-endian:
+    - magic "CPUS", then a u16 format version
+    - CPU state, in the order `Cpu::save_state` writes it
+    - bus state: a `MemoryMap` writes its region count and then each device in mapping order
+    devices decide their own layout; byte strings are prefixed with a u32 length
+*/
+
+pub const MAGIC: [u8; 4] = *b"CPUS";
+pub const VERSION: u16 = 1;
+
+/// Why a save state could not be restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The data does not start with [`MAGIC`].
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// The data ended in the middle of a value.
+    Truncated,
+    /// The state was saved from a machine with a different layout, or holds a value this
+    /// machine cannot take.
+    Mismatch(String),
+    /// Bytes were left over after the whole machine was restored.
+    TrailingBytes,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a save state"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            SnapshotError::Truncated => write!(f, "save state is truncated"),
+            SnapshotError::Mismatch(what) => write!(f, "save state does not fit this machine: {}", what),
+            SnapshotError::TrailingBytes => write!(f, "unexpected data after the end of the save state"),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+/// Accumulates state for a save state.
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.put_u8(value as u8);
+    }
+
+    pub fn put_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Writes a length-prefixed byte string.
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads back what a [`StateWriter`] wrote, in the same order.
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(SnapshotError::Mismatch(format!("invalid boolean {}", other))),
+        }
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, SnapshotError> {
+        let bytes: &[u8] = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut bytes: [u8; 4] = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes: [u8; 8] = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Reads a length-prefixed byte string.
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len: u32 = self.get_u32()?;
+        self.take(len as usize)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer: StateWriter = StateWriter::new();
+        writer.put_u8(7);
+        writer.put_bool(true);
+        writer.put_u16(0xABCD);
+        writer.put_u64(1 << 40);
+        writer.put_bytes(b"xyz");
+        let bytes: Vec<u8> = writer.into_bytes();
+
+        let mut reader: StateReader = StateReader::new(&bytes);
+        assert_eq!(reader.get_u8(), Ok(7));
+        assert_eq!(reader.get_bool(), Ok(true));
+        assert_eq!(reader.get_u16(), Ok(0xABCD));
+        assert_eq!(reader.get_u64(), Ok(1 << 40));
+        assert_eq!(reader.get_bytes(), Ok(&b"xyz"[..]));
+        assert!(reader.is_empty());
+        assert_eq!(reader.get_u8(), Err(SnapshotError::Truncated));
+
+        let mut reader: StateReader = StateReader::new(&[2]);
+        assert!(matches!(reader.get_bool(), Err(SnapshotError::Mismatch(_))));
+    }
+}
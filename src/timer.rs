@@ -0,0 +1,188 @@
+use std::io;
+
+use crate::bus::Bus;
+use crate::cpu::INTERRUPT_LINES;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+/*
+    timer registers, 0xF04 - 0xF08 in the standard memory map:
+    - 0xF04: control
+        - bit 0: enable, writing control with this bit set reloads the counter
+        - bit 1: raise an interrupt on underflow
+        - bit 2: periodic, reload the counter on underflow instead of stopping
+        - bit 7: underflow happened, cleared by any write to control
+    - 0xF05, 0xF06: reload value, high and low byte
+    - 0xF07, 0xF08: current counter, high and low byte (read only)
+    the counter decrements once per CPU cycle and underflows when it reaches 0
+*/
+
+pub const TIMER_START: u16 = 0xF04;
+pub const TIMER_END: u16 = 0xF08;
+pub const TIMER_CONTROL: u16 = 0xF04;
+pub const TIMER_RELOAD_HIGH: u16 = 0xF05;
+pub const TIMER_RELOAD_LOW: u16 = 0xF06;
+pub const TIMER_COUNTER_HIGH: u16 = 0xF07;
+pub const TIMER_COUNTER_LOW: u16 = 0xF08;
+
+pub const CONTROL_ENABLE: u8 = 0x01;
+pub const CONTROL_INTERRUPT: u8 = 0x02;
+pub const CONTROL_PERIODIC: u8 = 0x04;
+pub const CONTROL_UNDERFLOW: u8 = 0x80;
+
+/// Count-down timer that can raise an interrupt on `line` when it underflows.
+pub struct Timer {
+    line: u8,
+    control: u8,
+    reload: u16,
+    counter: u16,
+    interrupt_pending: bool,
+}
+
+impl Timer {
+    pub fn new(line: u8) -> Timer {
+        assert!(line < INTERRUPT_LINES, "no interrupt line {}", line);
+        Timer { line, control: 0, reload: 0, counter: 0, interrupt_pending: false }
+    }
+
+    fn underflow(&mut self) {
+        self.control |= CONTROL_UNDERFLOW;
+        if self.control & CONTROL_INTERRUPT != 0 {
+            self.interrupt_pending = true;
+        }
+        if self.control & CONTROL_PERIODIC != 0 && self.reload != 0 {
+            self.counter = self.reload;
+        } else {
+            self.control &= !CONTROL_ENABLE;
+        }
+    }
+}
+
+impl Bus for Timer {
+    fn read_u8(&mut self, address: u16) -> io::Result<u8> {
+        Ok(self.peek_u8(address))
+    }
+
+    fn write_u8(&mut self, address: u16, value: u8) -> io::Result<()> {
+        match address + TIMER_START {
+            TIMER_CONTROL => {
+                self.control = value & !CONTROL_UNDERFLOW;
+                if value & CONTROL_ENABLE != 0 {
+                    self.counter = self.reload;
+                }
+            }
+            TIMER_RELOAD_HIGH => self.reload = (self.reload & 0x00FF) | ((value as u16) << 8),
+            TIMER_RELOAD_LOW => self.reload = (self.reload & 0xFF00) | value as u16,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn peek_u8(&self, address: u16) -> u8 {
+        match address + TIMER_START {
+            TIMER_CONTROL => self.control,
+            TIMER_RELOAD_HIGH => (self.reload >> 8) as u8,
+            TIMER_RELOAD_LOW => self.reload as u8,
+            TIMER_COUNTER_HIGH => (self.counter >> 8) as u8,
+            TIMER_COUNTER_LOW => self.counter as u8,
+            _ => 0,
+        }
+    }
+
+    fn poke_u8(&mut self, address: u16, value: u8) {
+        // debuggers may set the counter directly
+        match address + TIMER_START {
+            TIMER_COUNTER_HIGH => self.counter = (self.counter & 0x00FF) | ((value as u16) << 8),
+            TIMER_COUNTER_LOW => self.counter = (self.counter & 0xFF00) | value as u16,
+            _ => {
+                let _ = self.write_u8(address, value);
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        let mut remaining: u64 = cycles;
+        while self.control & CONTROL_ENABLE != 0 && remaining > 0 {
+            if remaining < self.counter as u64 {
+                self.counter -= remaining as u16;
+                return;
+            }
+            remaining -= self.counter as u64;
+            self.counter = 0;
+            self.underflow();
+        }
+    }
+
+    fn take_interrupts(&mut self) -> u8 {
+        if std::mem::take(&mut self.interrupt_pending) {
+            1 << self.line
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.put_u8(self.control);
+        state.put_u16(self.reload);
+        state.put_u16(self.counter);
+        state.put_bool(self.interrupt_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), SnapshotError> {
+        self.control = state.get_u8()?;
+        self.reload = state.get_u16()?;
+        self.counter = state.get_u16()?;
+        self.interrupt_pending = state.get_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(timer: &mut Timer, address: u16, value: u8) {
+        timer.write_u8(address - TIMER_START, value).unwrap();
+    }
+
+    fn counter(timer: &Timer) -> u16 {
+        u16::from_be_bytes([timer.peek_u8(TIMER_COUNTER_HIGH - TIMER_START), timer.peek_u8(TIMER_COUNTER_LOW - TIMER_START)])
+    }
+
+    #[test]
+    fn test_one_shot() {
+        let mut timer: Timer = Timer::new(2);
+        write(&mut timer, TIMER_RELOAD_HIGH, 0x01);
+        write(&mut timer, TIMER_RELOAD_LOW, 0x00);
+        write(&mut timer, TIMER_CONTROL, CONTROL_ENABLE | CONTROL_INTERRUPT);
+
+        timer.tick(0xFF);
+        assert_eq!(counter(&timer), 1);
+        assert_eq!(timer.take_interrupts(), 0);
+
+        timer.tick(5);
+        assert_eq!(counter(&timer), 0);
+        assert_eq!(timer.peek_u8(0), CONTROL_INTERRUPT | CONTROL_UNDERFLOW);
+        assert_eq!(timer.take_interrupts(), 0b100);
+        assert_eq!(timer.take_interrupts(), 0);
+
+        // stopped after the first underflow
+        timer.tick(1000);
+        assert_eq!(timer.take_interrupts(), 0);
+    }
+
+    #[test]
+    fn test_periodic() {
+        let mut timer: Timer = Timer::new(0);
+        write(&mut timer, TIMER_RELOAD_LOW, 3);
+        write(&mut timer, TIMER_CONTROL, CONTROL_ENABLE | CONTROL_PERIODIC);
+
+        timer.tick(7);
+        assert_eq!(counter(&timer), 2);
+        assert_ne!(timer.peek_u8(0) & CONTROL_UNDERFLOW, 0);
+        assert_eq!(timer.take_interrupts(), 0);
+
+        write(&mut timer, TIMER_CONTROL, 0);
+        timer.tick(7);
+        assert_eq!(timer.peek_u8(0), 0);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::instruction::Instruction;
+
+/*
+    default cycle costs:
+    - 1: ALU operations, moves, skips, ldi, add i, ei, di, nop
+    - 2: jmp, ld, st
+    - 3: call, ret, reti and taking an interrupt
+    - 4: mul, ldm, stm
+    - 8: div, mod
+    a halted CPU and a faulting instruction consume no cycles
+*/
+
+/// A mnemonic that names no instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownMnemonic(pub String);
+
+impl fmt::Display for UnknownMnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown mnemonic `{}`", self.0)
+    }
+}
+
+impl Error for UnknownMnemonic {}
+
+/// Cost of each instruction, keyed by mnemonic, plus the cost of entering an interrupt handler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostTable {
+    costs: BTreeMap<&'static str, u32>,
+    interrupt: u32,
+}
+
+impl CostTable {
+    /// Every instruction, and taking an interrupt, costs `cost`.
+    pub fn uniform(cost: u32) -> CostTable {
+        let costs: BTreeMap<&'static str, u32> = Instruction::MNEMONICS.iter().map(|mnemonic| (*mnemonic, cost)).collect();
+        CostTable { costs, interrupt: cost }
+    }
+
+    pub fn get(&self, instruction: &Instruction) -> u32 {
+        self.costs.get(instruction.mnemonic()).copied().unwrap_or(0)
+    }
+
+    /// Sets the cost of every instruction with `mnemonic`.
+    pub fn set(&mut self, mnemonic: &str, cost: u32) -> Result<(), UnknownMnemonic> {
+        let key: &'static str = Instruction::MNEMONICS.iter().find(|known| **known == mnemonic).ok_or_else(|| UnknownMnemonic(mnemonic.to_string()))?;
+        self.costs.insert(key, cost);
+        Ok(())
+    }
+
+    pub fn get_interrupt(&self) -> u32 {
+        self.interrupt
+    }
+
+    pub fn set_interrupt(&mut self, cost: u32) {
+        self.interrupt = cost;
+    }
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        let mut table: CostTable = CostTable::uniform(1);
+        for (mnemonics, cost) in [
+            (&["jmp", "ld", "st"][..], 2),
+            (&["call", "ret", "reti"][..], 3),
+            (&["mul", "ldm", "stm"][..], 4),
+            (&["div", "mod"][..], 8),
+        ] {
+            for mnemonic in mnemonics {
+                table.set(mnemonic, cost).expect("default costs name known mnemonics");
+            }
+        }
+        table.set_interrupt(3);
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost_table() {
+        let mut table: CostTable = CostTable::default();
+        assert_eq!(table.get(&Instruction::decode(0x1012)), 1);
+        assert_eq!(table.get(&Instruction::decode(0xF406)), 1);
+        assert_eq!(table.get(&Instruction::decode(0xF102)), 2);
+        assert_eq!(table.get(&Instruction::decode(0xC129)), 8);
+        assert_eq!(table.get_interrupt(), 3);
+
+        table.set("add", 5).unwrap();
+        assert_eq!(table.get(&Instruction::decode(0x1012)), 5);
+        assert_eq!(table.get(&Instruction::decode(0xF406)), 5);
+        assert_eq!(table.set("ad", 5), Err(UnknownMnemonic("ad".to_string())));
+        assert_eq!(table.get(&Instruction::Unknown(0x0FFF)), 0);
+    }
+}
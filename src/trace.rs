@@ -0,0 +1,124 @@
+use std::io::{self, Write};
+
+use crate::instruction::Instruction;
+
+/*
+    binary trace format, all integers big-endian:
+    - header: magic "CPUT" and a u8 format version, written before the first record
+    - one record per executed instruction:
+        - u16 pc, u16 opcode, u8 stack pointer after the instruction
+        - u8 number of changed registers, then that many (u8 register, u8 new value) pairs
+    the mnemonic is not stored; decode the opcode to recover it
+*/
+
+pub const BINARY_MAGIC: [u8; 4] = *b"CPUT";
+pub const BINARY_VERSION: u8 = 1;
+
+/// What one executed instruction did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u16,
+    pub instruction: Instruction,
+    /// Registers whose value changed, with their new value, in register order.
+    pub changed: Vec<(u8, u8)>,
+    /// Stack pointer after the instruction.
+    pub stack_pointer: u8,
+}
+
+/// Receives a record for every instruction the CPU executes. Interrupt entries are not
+/// instructions and are not traced.
+pub trait Tracer {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()>;
+}
+
+/// One line per instruction, e.g. `0x004: 1012  add r0, r1 -> r2       sp=0  r2=0x05`.
+pub struct TextTracer<W: Write> {
+    output: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(output: W) -> TextTracer<W> {
+        TextTracer { output }
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        write!(self.output, "0x{:03x}: {:04x}  {:<22} sp={}", record.pc, record.opcode, record.instruction.to_string(), record.stack_pointer)?;
+        for (register, value) in &record.changed {
+            write!(self.output, "  r{}=0x{:02x}", register, value)?;
+        }
+        writeln!(self.output)
+    }
+}
+
+/// Compact records in the binary trace format.
+pub struct BinaryTracer<W: Write> {
+    output: W,
+    started: bool, // header written
+}
+
+impl<W: Write> BinaryTracer<W> {
+    pub fn new(output: W) -> BinaryTracer<W> {
+        BinaryTracer { output, started: false }
+    }
+}
+
+impl<W: Write> Tracer for BinaryTracer<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.started {
+            self.output.write_all(&BINARY_MAGIC)?;
+            self.output.write_all(&[BINARY_VERSION])?;
+            self.started = true;
+        }
+        let mut bytes: Vec<u8> = Vec::with_capacity(6 + 2 * record.changed.len());
+        bytes.extend_from_slice(&record.pc.to_be_bytes());
+        bytes.extend_from_slice(&record.opcode.to_be_bytes());
+        bytes.push(record.stack_pointer);
+        bytes.push(record.changed.len() as u8);
+        for (register, value) in &record.changed {
+            bytes.extend_from_slice(&[*register, *value]);
+        }
+        self.output.write_all(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::SharedBuffer;
+
+    fn record() -> TraceRecord {
+        TraceRecord {
+            pc: 0x004,
+            opcode: 0x1012,
+            instruction: Instruction::decode(0x1012),
+            changed: vec![(2, 5), (15, 0xFF)],
+            stack_pointer: 1,
+        }
+    }
+
+    #[test]
+    fn test_text_tracer() {
+        let output: SharedBuffer = SharedBuffer::new();
+        let mut tracer: TextTracer<SharedBuffer> = TextTracer::new(output.clone());
+        tracer.record(&record()).unwrap();
+
+        assert_eq!(String::from_utf8(output.contents()).unwrap(), "0x004: 1012  add r0, r1 -> r2       sp=1  r2=0x05  r15=0xff\n");
+    }
+
+    #[test]
+    fn test_binary_tracer() {
+        let output: SharedBuffer = SharedBuffer::new();
+        let mut tracer: BinaryTracer<SharedBuffer> = BinaryTracer::new(output.clone());
+        tracer.record(&record()).unwrap();
+        tracer.record(&record()).unwrap();
+
+        let entry: [u8; 10] = [0x00, 0x04, 0x10, 0x12, 1, 2, 2, 5, 15, 0xFF];
+        let mut expected: Vec<u8> = b"CPUT\x01".to_vec();
+        expected.extend_from_slice(&entry);
+        expected.extend_from_slice(&entry);
+        assert_eq!(output.contents(), expected);
+    }
+}